@@ -0,0 +1,7889 @@
+//! Aggregation of negotiated rates from JSONL billing reports
+//!
+//! Each input line holds a billing record whose negotiated prices are folded into an
+//! [`AccumulatedRate`]. [`records`] yields parsed records along with their accumulated rates, and
+//! [`process`] filters them and writes the kept records as CSV or JSON.
+
+use anyhow::Context;
+use arrow_array::{
+    ArrayRef, RecordBatch,
+    builder::{ArrayBuilder, Float64Builder, StringBuilder},
+};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{DataType, Schema, SchemaRef};
+use chrono::NaiveDate;
+use clap::ValueEnum;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use serde::{
+    Deserialize, Deserializer, Serialize,
+    de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor},
+};
+use serde_json::{Value, value::RawValue};
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt,
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::{self, BufRead, BufWriter, Read, Write},
+    num::NonZeroUsize,
+    ops::Add,
+    path::{Path, PathBuf},
+    rc::Rc,
+    str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{self, AtomicBool, AtomicUsize},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Billing record with the average of its negotiated rates
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct Record {
+    pub name: String,
+    pub billing_code: String,
+    /// Average negotiated rate, `None` if the record has no rates
+    pub avg_rate: Option<f64>,
+    #[serde(skip)]
+    pub billing_code_type: Option<String>,
+    /// Values of the fields listed in `--passthrough-fields`, in the same order, null if missing
+    #[serde(skip)]
+    pub passthrough: Vec<Value>,
+    /// Values pointed to by the `--project` projections, in the same order, null if missing
+    #[serde(skip)]
+    pub projected: Vec<Value>,
+    /// Negotiated rates the average is computed from, collected with `--with-rates`
+    #[serde(skip)]
+    pub rates: Vec<f64>,
+    /// Distinct negotiated rates and their number of occurrences, in increasing order of rate,
+    /// counted with `--rate-frequencies`
+    #[serde(skip)]
+    pub rate_frequencies: Vec<(f64, u64)>,
+    /// Input object the record was parsed from, kept with `--format enriched-json`
+    #[serde(skip)]
+    pub source: Option<serde_json::Map<String, Value>>,
+    /// Date read from `--date-field` when filtering on dates
+    #[serde(skip)]
+    pub date: Option<NaiveDate>,
+}
+
+impl Record {
+    /// Returns a description of the record and its average rate for diagnostics
+    fn describe_rate(&self) -> String {
+        let avg_rate = self
+            .avg_rate
+            .map_or_else(|| "no rate".into(), |r| format!("average rate {r}"));
+        format!("{} ({}) has {avg_rate}", self.name, self.billing_code)
+    }
+
+    /// Returns the values of `fields` for this record, using an empty string for missing values
+    fn key(&self, fields: &[KeyField]) -> Vec<String> {
+        fields
+            .iter()
+            .map(|field| match field {
+                KeyField::BillingCode => self.billing_code.clone(),
+                KeyField::BillingCodeType => self.billing_code_type.clone().unwrap_or_default(),
+            })
+            .collect()
+    }
+}
+
+/// Record field usable in a grouping key
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum KeyField {
+    BillingCode,
+    BillingCodeType,
+}
+
+/// Format of the input records
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum InputFormat {
+    /// One JSON object per line with the negotiated rates to average
+    #[default]
+    Jsonl,
+    /// CSV with a header and the columns of the default output, averages being already computed
+    Csv,
+}
+
+/// Format of the output rows
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    /// CSV with a header
+    #[default]
+    Csv,
+    /// JSON array of objects
+    Json,
+    /// One JSON object per line
+    Ndjson,
+    /// Arrow IPC stream of record batches, only supported for the name, billing code and average
+    /// rate columns
+    Arrow,
+    /// Input object of each record, one per line, with its average rate added as `avg_rate`
+    EnrichedJson,
+    /// JSON object mapping each billing code to the array of its rows, without their billing code
+    ///
+    /// Rows are held in memory until the whole input is read.
+    GroupedJson,
+    /// Text table with aligned columns, for viewing small outputs in a terminal
+    ///
+    /// Rows are held in memory until the whole input is read, up to `--table-max-rows`.
+    Table,
+}
+
+/// Quoting of fields in CSV output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum CsvQuote {
+    /// Quote fields only when necessary, e.g. when they contain a delimiter or a quote
+    #[default]
+    Necessary,
+    /// Quote every field
+    Always,
+    /// Quote every field that is not a number
+    NonNumeric,
+    /// Never quote fields, even if it produces invalid CSV
+    Never,
+}
+
+impl From<CsvQuote> for csv::QuoteStyle {
+    fn from(quote: CsvQuote) -> Self {
+        match quote {
+            CsvQuote::Necessary => csv::QuoteStyle::Necessary,
+            CsvQuote::Always => csv::QuoteStyle::Always,
+            CsvQuote::NonNumeric => csv::QuoteStyle::NonNumeric,
+            CsvQuote::Never => csv::QuoteStyle::Never,
+        }
+    }
+}
+
+/// Representation of null values in JSON output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum JsonNullMode {
+    /// Write fields with a `null` value
+    #[default]
+    Null,
+    /// Leave null fields out of their rows
+    Omit,
+}
+
+/// Handling of negotiated rates of zero, which can stand for a missing rate
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ZeroRatePolicy {
+    /// Aggregate zero rates like any other
+    #[default]
+    Include,
+    /// Skip zero rates
+    Exclude,
+    /// Fail on a zero rate, like on any invalid value
+    Error,
+}
+
+/// Handling of non-finite rates when computing minimum and maximum rates
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum NonFiniteMinMax {
+    /// Skip non-finite rates
+    #[default]
+    Ignore,
+    /// Include non-finite rates, a NaN making the minimum and maximum NaN
+    Propagate,
+}
+
+impl NonFiniteMinMax {
+    /// Returns the smaller of `a` and `b` according to this policy
+    fn min(self, a: f64, b: f64) -> f64 {
+        match self {
+            Self::Propagate if a.is_nan() || b.is_nan() => f64::NAN,
+            _ => a.min(b),
+        }
+    }
+
+    /// Returns the larger of `a` and `b` according to this policy
+    fn max(self, a: f64, b: f64) -> f64 {
+        match self {
+            Self::Propagate if a.is_nan() || b.is_nan() => f64::NAN,
+            _ => a.max(b),
+        }
+    }
+}
+
+/// Statistic of the negotiated rates of a record written as its average rate
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Aggregation {
+    /// Arithmetic mean of the rates
+    #[default]
+    Mean,
+    /// Median of the rates, weighted with `--weight-field`
+    Median,
+    /// Most common rate, rounded with `--mode-precision`, the lowest one in case of a tie
+    Mode,
+    /// Geometric mean of the positive rates, other rates being skipped as their logarithm is
+    /// undefined
+    Geomean,
+}
+
+/// Rounding of rates to integers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rounding {
+    /// Round halves to the nearest even integer (e.g. 12.5 to 12 and 13.5 to 14)
+    #[default]
+    HalfEven,
+    /// Round halves away from zero (e.g. 12.5 to 13 and -12.5 to -13)
+    HalfUp,
+    /// Round down (e.g. 12.7 to 12 and -12.2 to -13)
+    Floor,
+    /// Round up (e.g. 12.2 to 13 and -12.7 to -12)
+    Ceil,
+    /// Round towards zero (e.g. 12.7 to 12 and -12.7 to -12)
+    Trunc,
+}
+
+impl Rounding {
+    /// Returns `x` rounded to an integer according to this mode
+    fn apply(self, x: f64) -> f64 {
+        match self {
+            Self::HalfEven => x.round_ties_even(),
+            Self::HalfUp => x.round(),
+            Self::Floor => x.floor(),
+            Self::Ceil => x.ceil(),
+            Self::Trunc => x.trunc(),
+        }
+    }
+}
+
+/// Format of progress reports
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProgressFormat {
+    /// Human-readable line
+    #[default]
+    Text,
+    /// JSON object per line
+    Json,
+}
+
+/// Range of average rates expected for records with a billing code, parsed from
+/// `billing_code,min,max`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ExpectedRange {
+    pub billing_code: String,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl ExpectedRange {
+    /// Returns whether `rate` is within this inclusive range
+    pub fn contains(&self, rate: f64) -> bool {
+        (self.min..=self.max).contains(&rate)
+    }
+}
+
+impl FromStr for ExpectedRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let [billing_code, min, max] = s.split(',').map(str::trim).collect::<Vec<_>>()[..] else {
+            anyhow::bail!("expected `billing_code,min,max`");
+        };
+        let bound = |b: &str| {
+            b.parse::<f64>()
+                .with_context(|| format!("invalid rate `{b}`"))
+        };
+        Ok(Self {
+            billing_code: billing_code.into(),
+            min: bound(min)?,
+            max: bound(max)?,
+        })
+    }
+}
+
+/// Output column taking the value at a JSON pointer in each input object, parsed from
+/// `name=pointer`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Projection {
+    pub name: String,
+    pub pointer: String,
+}
+
+impl FromStr for Projection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((name, pointer)) = s.split_once('=') else {
+            anyhow::bail!("expected `name=pointer`");
+        };
+        if name.is_empty() {
+            anyhow::bail!("missing column name");
+        }
+        if !pointer.is_empty() && !pointer.starts_with('/') {
+            anyhow::bail!("invalid JSON pointer `{pointer}`: expected a leading `/`");
+        }
+        Ok(Self {
+            name: name.into(),
+            pointer: pointer.into(),
+        })
+    }
+}
+
+/// Input field read as a record field, parsed from `field=input_field`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FieldMapping {
+    pub field: String,
+    pub input: String,
+}
+
+/// Record fields that can be read from other input fields, the rates field being set with
+/// `--rates-field`
+const MAPPABLE_FIELDS: [&str; 3] = ["name", "billing_code", "billing_code_type"];
+
+impl FromStr for FieldMapping {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((field, input)) = s.split_once('=') else {
+            anyhow::bail!("expected `field=input_field`");
+        };
+        if !MAPPABLE_FIELDS.contains(&field) {
+            anyhow::bail!(
+                "unknown record field `{field}`, expected one of: {}",
+                MAPPABLE_FIELDS.join(", ")
+            );
+        }
+        if input.is_empty() {
+            anyhow::bail!("missing input field name");
+        }
+        Ok(Self {
+            field: field.into(),
+            input: input.into(),
+        })
+    }
+}
+
+/// Subset of billing codes processed by one of several runs, parsed from `index/count`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Shard {
+    /// Index of the shard, from 1 to `count`
+    pub index: u64,
+    pub count: u64,
+}
+
+impl Shard {
+    /// Returns whether records with `billing_code` belong to this shard
+    ///
+    /// Codes are assigned to shards by their 64-bit FNV-1a hash, which does not depend on the
+    /// platform nor on the version of the program.
+    pub fn contains(&self, billing_code: &str) -> bool {
+        let hash = billing_code
+            .bytes()
+            .fold(0xcbf29ce484222325, |hash: u64, b| {
+                (hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+            });
+        hash % self.count == self.index - 1
+    }
+}
+
+impl FromStr for Shard {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((index, count)) = s.split_once('/') else {
+            anyhow::bail!("expected `index/count`");
+        };
+        let number = |n: &str| {
+            n.trim()
+                .parse::<u64>()
+                .with_context(|| format!("invalid number `{n}`"))
+        };
+        let (index, count) = (number(index)?, number(count)?);
+        if !(1..=count).contains(&index) {
+            anyhow::bail!("shard index must be between 1 and {count}, got {index}");
+        }
+        Ok(Self { index, count })
+    }
+}
+
+/// Transformation applied to record names before writing them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum NameNormalization {
+    /// Keep names as they are
+    #[default]
+    None,
+    /// Remove leading and trailing whitespace
+    Trim,
+    /// Convert to lowercase
+    Lower,
+    /// Convert to uppercase
+    Upper,
+    /// Capitalize the first letter of each word and lowercase the others
+    Title,
+}
+
+impl NameNormalization {
+    /// Returns `name` normalized
+    pub fn apply(self, name: String) -> String {
+        match self {
+            Self::None => name,
+            Self::Trim => name.trim().into(),
+            Self::Lower => name.to_lowercase(),
+            Self::Upper => name.to_uppercase(),
+            Self::Title => {
+                let mut title = String::with_capacity(name.len());
+                let mut word_start = true;
+                for c in name.chars() {
+                    if word_start {
+                        title.extend(c.to_uppercase());
+                    } else {
+                        title.extend(c.to_lowercase());
+                    }
+                    word_start = !c.is_alphanumeric();
+                }
+                title
+            }
+        }
+    }
+}
+
+/// Normalization applied to billing codes before matching them against filters
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum CodeNormalization {
+    /// Match billing codes exactly
+    #[default]
+    None,
+    /// Convert to uppercase
+    Upper,
+    /// Remove leading zeros, keeping a single zero for codes made of zeros
+    StripLeadingZeros,
+}
+
+impl CodeNormalization {
+    /// Returns `code` normalized
+    pub fn apply(self, code: &str) -> Cow<'_, str> {
+        match self {
+            Self::None => code.into(),
+            Self::Upper => code.to_uppercase().into(),
+            Self::StripLeadingZeros => match code.trim_start_matches('0') {
+                "" if !code.is_empty() => "0".into(),
+                stripped => stripped.into(),
+            },
+        }
+    }
+}
+
+/// Individual negotiated price written with `--explode-prices`
+#[derive(Debug, Serialize)]
+struct PriceRecord<'a> {
+    name: &'a str,
+    billing_code: &'a str,
+    negotiated_rate: f64,
+}
+
+/// Deserializes a [`Record`] along with the rates it aggregates, reading negotiated rates from the
+/// field configured in `options`
+struct RecordSeed<'a> {
+    options: &'a ProcessOptions,
+}
+
+impl<'de> DeserializeSeed<'de> for RecordSeed<'_> {
+    type Value = (Record, AccumulatedRate);
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for RecordSeed<'_> {
+    type Value = (Record, AccumulatedRate);
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a billing record")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let rates_field = self.options.rates_field.as_str();
+        let mut name = None;
+        let mut billing_code = None;
+        let mut billing_code_type = None;
+        let mut rate = None;
+        let passthrough_fields = self.options.passthrough_fields.as_slice();
+        let mut passthrough = vec![Value::Null; passthrough_fields.len()];
+        let options = self.options;
+        let name_field = options.input_field("name");
+        let billing_code_field = options.input_field("billing_code");
+        let billing_code_type_field = options.input_field("billing_code_type");
+        let date_field = options
+            .filters_dates()
+            .then_some(options.date_field.as_str());
+        let mut date = None;
+        let seed = || FieldSeed {
+            rates_field,
+            name_field,
+            billing_code_field,
+            billing_code_type_field,
+            date_field,
+            passthrough_fields,
+        };
+        let duplicate = |field| de::Error::custom(format_args!("duplicate field `{field}`"));
+        let missing = |field| de::Error::custom(format_args!("missing field `{field}`"));
+        while let Some(field) = map.next_key_seed(seed())? {
+            match field {
+                Field::Name if name.is_some() => return Err(duplicate(name_field)),
+                Field::Name => name = Some(map.next_value()?),
+                Field::BillingCode if billing_code.is_some() => {
+                    return Err(duplicate(billing_code_field));
+                }
+                Field::BillingCode => billing_code = Some(map.next_value()?),
+                Field::BillingCodeType if billing_code_type.is_some() => {
+                    return Err(duplicate(billing_code_type_field));
+                }
+                Field::BillingCodeType => billing_code_type = Some(map.next_value()?),
+                Field::Rates if rate.is_some() => return Err(duplicate(rates_field)),
+                Field::Rates => {
+                    rate = Some(map.next_value_seed(RatesSeed {
+                        options: self.options,
+                    })?);
+                }
+                Field::Date if date.is_some() => {
+                    return Err(duplicate(&options.date_field));
+                }
+                Field::Date => {
+                    let value: Option<String> = map.next_value()?;
+                    let parsed = value
+                        .map(|value| {
+                            value.parse::<NaiveDate>().map_err(|e| {
+                                de::Error::custom(format_args!("invalid date `{value}`: {e}"))
+                            })
+                        })
+                        .transpose()?;
+                    date = Some(parsed);
+                }
+                Field::Passthrough(i) => passthrough[i] = map.next_value()?,
+                Field::Other => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        let rate = rate.ok_or_else(|| missing(rates_field))?;
+        let default = |value: &String| options.default_missing.then(|| value.clone());
+        let record = Record {
+            name: name
+                .or_else(|| default(&options.default_name))
+                .ok_or_else(|| missing(name_field))?,
+            billing_code: billing_code
+                .or_else(|| default(&options.default_billing_code))
+                .ok_or_else(|| missing(billing_code_field))?,
+            avg_rate: timed(Stage::Aggregate, || rate.aggregate()),
+            billing_code_type: billing_code_type.flatten(),
+            passthrough,
+            projected: Vec::new(),
+            rates: Vec::new(),
+            rate_frequencies: Vec::new(),
+            source: None,
+            date: date.flatten(),
+        };
+        Ok((record, rate))
+    }
+}
+
+enum Field {
+    Name,
+    BillingCode,
+    BillingCodeType,
+    Rates,
+    /// Field named by `--date-field`
+    Date,
+    /// Field at this index in `--passthrough-fields`
+    Passthrough(usize),
+    Other,
+}
+
+/// Identifies record fields without allocating, given their runtime names in the input
+struct FieldSeed<'a> {
+    rates_field: &'a str,
+    name_field: &'a str,
+    billing_code_field: &'a str,
+    billing_code_type_field: &'a str,
+    /// Name of the date field, if dates are read
+    date_field: Option<&'a str>,
+    passthrough_fields: &'a [String],
+}
+
+impl<'de> DeserializeSeed<'de> for FieldSeed<'_> {
+    type Value = Field;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_identifier(self)
+    }
+}
+
+impl<'de> Visitor<'de> for FieldSeed<'_> {
+    type Value = Field;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a field name")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(match v {
+            _ if v == self.rates_field => Field::Rates,
+            _ if v == self.name_field => Field::Name,
+            _ if v == self.billing_code_field => Field::BillingCode,
+            _ if v == self.billing_code_type_field => Field::BillingCodeType,
+            _ if Some(v) == self.date_field => Field::Date,
+            _ => match self.passthrough_fields.iter().position(|f| f == v) {
+                Some(i) => Field::Passthrough(i),
+                None => Field::Other,
+            },
+        })
+    }
+}
+
+/// Sum, count, spread and extrema of rates
+///
+/// The sum uses Neumaier summation to stay accurate regardless of the number and order of rates.
+/// The variance uses Welford's algorithm, combining partial results with Chan's formula.
+#[derive(Debug, Default)]
+pub struct AccumulatedRate {
+    rate: f64,
+    /// Running compensation for the low-order bits lost when adding to `rate`
+    compensation: f64,
+    count: u64,
+    /// Running mean of the rates and sum of their squared deviations from it
+    mean: f64,
+    m2: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+    /// Individual prices, only collected when `options` need them
+    prices: Option<Vec<f64>>,
+    /// Number of prices listed in each group, only collected when `options` need them
+    group_sizes: Option<Vec<u64>>,
+    /// Individual prices and their weights, only collected for `--aggregation median`
+    weighted_prices: Option<Vec<(f64, f64)>>,
+    /// Number of occurrences of each rounded price, keyed by its bits, only collected for
+    /// `--aggregation mode` and `--rate-frequencies`
+    price_counts: Option<HashMap<u64, u64>>,
+    /// Decimal places prices are rounded to before being counted, and how
+    mode_precision: Option<u32>,
+    rounding: Rounding,
+    /// Sum of the natural logarithms of the positive prices and their number, only accumulated
+    /// for `--aggregation geomean`
+    log_sum: f64,
+    log_count: u64,
+    nonfinite_minmax: NonFiniteMinMax,
+    aggregation: Aggregation,
+}
+
+impl AccumulatedRate {
+    fn new(options: &ProcessOptions) -> Self {
+        Self {
+            prices: (options.explode_prices || options.measure_cardinality || options.with_rates)
+                .then(Vec::new),
+            group_sizes: options.group_size_histogram.then(Vec::new),
+            weighted_prices: (options.aggregation == Aggregation::Median).then(Vec::new),
+            price_counts: (options.aggregation == Aggregation::Mode || options.rate_frequencies)
+                .then(HashMap::new),
+            mode_precision: options.mode_precision,
+            rounding: options.rounding,
+            nonfinite_minmax: options.nonfinite_minmax,
+            aggregation: options.aggregation,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the sum of the rates
+    pub fn sum(&self) -> f64 {
+        self.rate + self.compensation
+    }
+
+    /// Returns the number of rates
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the smallest rate, `None` if there are no rates
+    pub fn min(&self) -> Option<f64> {
+        self.min
+    }
+
+    /// Returns the largest rate, `None` if there are no rates
+    pub fn max(&self) -> Option<f64> {
+        self.max
+    }
+
+    /// Returns the average rate, `None` if there are no rates
+    pub fn average(&self) -> Option<f64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.sum() / self.count as f64)
+        }
+    }
+
+    /// Returns the weighted median of the rates, `None` if there are no rates, their total weight
+    /// is zero or they were not collected
+    ///
+    /// The median is the smallest rate at which the cumulative weight of the sorted rates reaches
+    /// half of the total weight. If it reaches exactly half, the median is the midpoint between
+    /// that rate and the next one, which gives the usual median when all weights are equal.
+    pub fn median(&self) -> Option<f64> {
+        let mut prices = self.weighted_prices.clone()?;
+        prices.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let half = prices.iter().map(|&(_, weight)| weight).sum::<f64>() / 2.0;
+        if half <= 0.0 {
+            return None;
+        }
+        let mut cumulative = 0.0;
+        let mut prices = prices.into_iter().filter(|&(_, weight)| weight > 0.0);
+        while let Some((rate, weight)) = prices.next() {
+            cumulative += weight;
+            if cumulative == half {
+                return Some(prices.next().map_or(rate, |(next, _)| (rate + next) / 2.0));
+            }
+            if cumulative > half {
+                return Some(rate);
+            }
+        }
+        None
+    }
+
+    /// Returns the most common rate, `None` if there are no rates or they were not counted
+    ///
+    /// Rates are rounded to `--mode-precision` decimal places before being counted. Ties are
+    /// broken toward the lowest rate.
+    pub fn mode(&self) -> Option<f64> {
+        self.price_counts
+            .as_ref()?
+            .iter()
+            .map(|(&bits, &count)| (f64::from_bits(bits), count))
+            .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.total_cmp(&a.0)))
+            .map(|(rate, _)| rate)
+    }
+
+    /// Returns the distinct rates and their number of occurrences, in increasing order of rate,
+    /// empty if they were not counted
+    fn frequencies(&self) -> Vec<(f64, u64)> {
+        let mut frequencies = self
+            .price_counts
+            .iter()
+            .flatten()
+            .map(|(&bits, &count)| (f64::from_bits(bits), count))
+            .collect::<Vec<_>>();
+        frequencies.sort_by(|a, b| a.0.total_cmp(&b.0));
+        frequencies
+    }
+
+    /// Returns the geometric mean of the positive rates, `None` if there are none or they were
+    /// not accumulated
+    pub fn geometric_mean(&self) -> Option<f64> {
+        (self.log_count > 0).then(|| (self.log_sum / self.log_count as f64).exp())
+    }
+
+    /// Returns the statistic of `--aggregation`, `None` if there are no rates
+    pub fn aggregate(&self) -> Option<f64> {
+        match self.aggregation {
+            Aggregation::Mean => self.average(),
+            Aggregation::Median => self.median(),
+            Aggregation::Mode => self.mode(),
+            Aggregation::Geomean => self.geometric_mean(),
+        }
+    }
+
+    /// Returns the sample variance of the rates, `None` if there are fewer than two rates
+    pub fn variance(&self) -> Option<f64> {
+        (self.count >= 2).then(|| self.m2 / (self.count - 1) as f64)
+    }
+
+    /// Returns the sample standard deviation of the rates, `None` if there are fewer than two
+    /// rates
+    pub fn std_dev(&self) -> Option<f64> {
+        self.variance().map(f64::sqrt)
+    }
+
+    /// Returns the ratio of the standard deviation to the average of the rates, `None` if there
+    /// are fewer than two rates
+    pub fn coefficient_of_variation(&self) -> Option<f64> {
+        Some(self.std_dev()? / self.average()?)
+    }
+
+    /// Returns the individual rates if they were collected
+    pub fn prices(&self) -> Option<&[f64]> {
+        self.prices.as_deref()
+    }
+
+    /// Adds the rates accumulated in `other`
+    fn merge(&mut self, other: AccumulatedRate) {
+        timed(Stage::Aggregate, || self.merge_untimed(other));
+    }
+
+    fn merge_untimed(&mut self, other: AccumulatedRate) {
+        self.add_to_sum(other.rate);
+        self.compensation += other.compensation;
+        let count = self.count + other.count;
+        if other.count > 0 {
+            let delta = other.mean - self.mean;
+            let weight = other.count as f64 / count as f64;
+            self.mean += delta * weight;
+            self.m2 += other.m2 + delta * delta * self.count as f64 * weight;
+        }
+        self.count = count;
+        self.add_extrema(other.min, other.max);
+        if let (Some(prices), Some(more)) = (&mut self.prices, other.prices) {
+            prices.extend(more);
+        }
+        if let (Some(group_sizes), Some(more)) = (&mut self.group_sizes, other.group_sizes) {
+            group_sizes.extend(more);
+        }
+        if let (Some(prices), Some(more)) = (&mut self.weighted_prices, other.weighted_prices) {
+            prices.extend(more);
+        }
+        if let (Some(counts), Some(more)) = (&mut self.price_counts, other.price_counts) {
+            for (bits, count) in more {
+                *counts.entry(bits).or_default() += count;
+            }
+        }
+        self.log_sum += other.log_sum;
+        self.log_count += other.log_count;
+    }
+
+    /// Adds a price with the given weight in the median
+    fn add_price(&mut self, x: f64, weight: f64) {
+        timed(Stage::Aggregate, || self.add_price_untimed(x, weight));
+    }
+
+    fn add_price_untimed(&mut self, x: f64, weight: f64) {
+        self.add_to_sum(x);
+        self.count += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (x - self.mean);
+        self.add_extrema(Some(x), Some(x));
+        if let Some(prices) = &mut self.prices {
+            prices.push(x);
+        }
+        if let Some(prices) = &mut self.weighted_prices {
+            prices.push((x, weight));
+        }
+        if let Some(counts) = &mut self.price_counts {
+            let rounded = match self.mode_precision {
+                Some(places) => {
+                    let factor = 10f64.powi(places as i32);
+                    self.rounding.apply(x * factor) / factor
+                }
+                None => x,
+            };
+            // Adding zero turns -0.0 into 0.0 so both are counted as the same rate
+            *counts.entry((rounded + 0.0).to_bits()).or_default() += 1;
+        }
+        if self.aggregation == Aggregation::Geomean && x > 0.0 {
+            self.log_sum += x.ln();
+            self.log_count += 1;
+        }
+    }
+
+    fn add_extrema(&mut self, min: Option<f64>, max: Option<f64>) {
+        let policy = self.nonfinite_minmax;
+        let included = |x: &f64| policy == NonFiniteMinMax::Propagate || x.is_finite();
+        let min = min.filter(included);
+        let max = max.filter(included);
+        self.min = self
+            .min
+            .into_iter()
+            .chain(min)
+            .reduce(|a, b| policy.min(a, b));
+        self.max = self
+            .max
+            .into_iter()
+            .chain(max)
+            .reduce(|a, b| policy.max(a, b));
+    }
+
+    fn add_to_sum(&mut self, x: f64) {
+        let sum = self.rate + x;
+        self.compensation += if self.rate.abs() >= x.abs() {
+            (self.rate - sum) + x
+        } else {
+            (x - sum) + self.rate
+        };
+        self.rate = sum;
+    }
+}
+
+impl From<AccumulatedRate> for Option<f64> {
+    fn from(value: AccumulatedRate) -> Self {
+        value.average()
+    }
+}
+
+impl Add<NegotiatedRate> for AccumulatedRate {
+    type Output = Self;
+
+    fn add(mut self, rhs: NegotiatedRate) -> Self::Output {
+        self.merge(rhs.negotiated_prices);
+        self
+    }
+}
+
+impl Add<NegotiatedPrice> for AccumulatedRate {
+    type Output = Self;
+
+    fn add(mut self, rhs: NegotiatedPrice) -> Self::Output {
+        self.add_price(rhs.negotiated_rate, 1.0);
+        self
+    }
+}
+
+#[derive(Debug)]
+struct NegotiatedRate {
+    negotiated_prices: AccumulatedRate,
+}
+
+/// Deserializes the array of negotiated rates of a record, folding it into an [`AccumulatedRate`]
+struct RatesSeed<'a> {
+    options: &'a ProcessOptions,
+}
+
+impl<'de> DeserializeSeed<'de> for RatesSeed<'_> {
+    type Value = AccumulatedRate;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for RatesSeed<'_> {
+    type Value = AccumulatedRate;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a sequence of negotiated rates")
+    }
+
+    fn visit_seq<S>(self, mut seq: S) -> Result<Self::Value, S::Error>
+    where
+        S: SeqAccess<'de>,
+    {
+        let mut acc = AccumulatedRate::new(self.options);
+        let mut groups = 0u64;
+        while let Some(rate) = seq.next_element_seed(NegotiatedRateSeed {
+            options: self.options,
+        })? {
+            groups += 1;
+            if groups.is_multiple_of(DEADLINE_CHECK_INTERVAL) {
+                check_deadline(self.options)?;
+            }
+            acc = acc + rate;
+        }
+        Ok(acc)
+    }
+}
+
+/// Deserializes a group of negotiated prices
+struct NegotiatedRateSeed<'a> {
+    options: &'a ProcessOptions,
+}
+
+impl<'de> DeserializeSeed<'de> for NegotiatedRateSeed<'_> {
+    type Value = NegotiatedRate;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for NegotiatedRateSeed<'_> {
+    type Value = NegotiatedRate;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a negotiated rate")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut negotiated_prices = None;
+        while let Some(field) = map.next_key()? {
+            match field {
+                NegotiatedRateField::NegotiatedPrices if negotiated_prices.is_some() => {
+                    return Err(de::Error::duplicate_field("negotiated_prices"));
+                }
+                NegotiatedRateField::NegotiatedPrices => {
+                    negotiated_prices = Some(map.next_value_seed(PricesSeed {
+                        options: self.options,
+                    })?);
+                }
+                NegotiatedRateField::Other => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(NegotiatedRate {
+            negotiated_prices: negotiated_prices
+                .ok_or_else(|| de::Error::missing_field("negotiated_prices"))?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "snake_case")]
+enum NegotiatedRateField {
+    NegotiatedPrices,
+    #[serde(other)]
+    Other,
+}
+
+/// Deserializes an array of negotiated prices, folding it into an [`AccumulatedRate`]
+///
+/// Prices past `--max-prices-per-group` are skipped without being validated.
+struct PricesSeed<'a> {
+    options: &'a ProcessOptions,
+}
+
+impl<'de> DeserializeSeed<'de> for PricesSeed<'_> {
+    type Value = AccumulatedRate;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for PricesSeed<'_> {
+    type Value = AccumulatedRate;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a sequence of negotiated prices")
+    }
+
+    fn visit_seq<S>(self, mut seq: S) -> Result<Self::Value, S::Error>
+    where
+        S: SeqAccess<'de>,
+    {
+        let mut acc = AccumulatedRate::new(self.options);
+        let limit = self.options.prices_per_group().unwrap_or(u64::MAX);
+        let mut size = 0;
+        while size < limit {
+            let price = seq.next_element_seed(PriceSeed {
+                weight_field: self.options.weight_field.as_deref(),
+                strict_numbers: self.options.strict_numbers,
+            })?;
+            let Some((price, weight)) = price else {
+                break;
+            };
+            match (price.negotiated_rate == 0.0, self.options.zero_rate_policy) {
+                (true, ZeroRatePolicy::Exclude) => {}
+                (true, ZeroRatePolicy::Error) => {
+                    return Err(de::Error::custom("negotiated rate is zero"));
+                }
+                _ => acc.add_price(price.negotiated_rate, weight),
+            }
+            size += 1;
+            if size.is_multiple_of(DEADLINE_CHECK_INTERVAL) {
+                check_deadline(self.options)?;
+            }
+        }
+        if size == limit {
+            while seq.next_element::<IgnoredAny>()?.is_some() {
+                size += 1;
+                if size.is_multiple_of(DEADLINE_CHECK_INTERVAL) {
+                    check_deadline(self.options)?;
+                }
+            }
+        }
+        if size < self.options.min_prices_per_group {
+            acc = AccumulatedRate::new(self.options);
+        }
+        if let Some(group_sizes) = &mut acc.group_sizes {
+            group_sizes.push(size);
+        }
+        Ok(acc)
+    }
+}
+
+thread_local! {
+    /// Time by which the record being parsed on the current thread must be aggregated
+    static RECORD_DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Number of prices or groups of prices aggregated between checks of the deadline of a record
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+/// Guard setting the deadline of the record parsed on the current thread until it is dropped
+struct RecordDeadline;
+
+impl RecordDeadline {
+    fn start(timeout: Duration) -> Self {
+        RECORD_DEADLINE.set(Instant::now().checked_add(timeout));
+        Self
+    }
+}
+
+impl Drop for RecordDeadline {
+    fn drop(&mut self) {
+        RECORD_DEADLINE.set(None);
+    }
+}
+
+/// Fails if the record being parsed is past its `--per-record-timeout`
+fn check_deadline<E: de::Error>(options: &ProcessOptions) -> Result<(), E> {
+    match (RECORD_DEADLINE.get(), options.per_record_timeout) {
+        (Some(deadline), Some(timeout)) if Instant::now() > deadline => Err(E::custom(format!(
+            "record took longer than {timeout:?} to aggregate"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Parses a duration in seconds, or with a unit among `ms`, `s`, `m` and `h`
+fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let (number, unit) = match s.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let number = number
+        .trim()
+        .parse::<f64>()
+        .with_context(|| format!("invalid duration `{s}`"))?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => anyhow::bail!("unknown unit `{unit}`, expected one of: ms, s, m, h"),
+    };
+    Duration::try_from_secs_f64(seconds).with_context(|| format!("invalid duration `{s}`"))
+}
+
+#[derive(Debug)]
+struct NegotiatedPrice {
+    negotiated_rate: f64,
+}
+
+/// Deserializes a negotiated price and its weight from `weight_field`, or a weight of 1 without
+/// weight field
+struct PriceSeed<'a> {
+    weight_field: Option<&'a str>,
+    /// Whether integer rates that are not exactly representable as `f64` are rejected
+    strict_numbers: bool,
+}
+
+impl<'de> DeserializeSeed<'de> for PriceSeed<'_> {
+    type Value = (NegotiatedPrice, f64);
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for PriceSeed<'_> {
+    type Value = (NegotiatedPrice, f64);
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a negotiated price")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let weight_field = self.weight_field;
+        let mut negotiated_rate = None;
+        let mut weight = None;
+        while let Some(field) = map.next_key_seed(PriceFieldSeed { weight_field })? {
+            match field {
+                PriceField::Rate if negotiated_rate.is_some() => {
+                    return Err(de::Error::duplicate_field("negotiated_rate"));
+                }
+                PriceField::Rate => {
+                    negotiated_rate = Some(map.next_value_seed(RateSeed {
+                        strict: self.strict_numbers,
+                    })?);
+                }
+                PriceField::Weight if weight.is_some() => {
+                    return Err(de::Error::custom(format_args!(
+                        "duplicate field `{}`",
+                        weight_field.unwrap_or_default()
+                    )));
+                }
+                PriceField::Weight => {
+                    let w: f64 = map.next_value()?;
+                    if !(w.is_finite() && w >= 0.0) {
+                        return Err(de::Error::custom(format_args!("invalid weight {w}")));
+                    }
+                    weight = Some(w);
+                }
+                PriceField::Other => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        let negotiated_rate =
+            negotiated_rate.ok_or_else(|| de::Error::missing_field("negotiated_rate"))?;
+        let weight = match (weight, weight_field) {
+            (Some(weight), _) => weight,
+            (None, None) => 1.0,
+            (None, Some(weight_field)) => {
+                return Err(de::Error::custom(format_args!(
+                    "missing field `{weight_field}`"
+                )));
+            }
+        };
+        Ok((NegotiatedPrice { negotiated_rate }, weight))
+    }
+}
+
+/// Largest integer such that it and all smaller integers are exactly representable as `f64`
+const MAX_EXACT_INTEGER: u64 = 1 << f64::MANTISSA_DIGITS;
+
+/// Deserializes a negotiated rate, rejecting integers too large to be exactly represented as
+/// `f64` if `strict`
+struct RateSeed {
+    strict: bool,
+}
+
+impl<'de> DeserializeSeed<'de> for RateSeed {
+    type Value = f64;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        if !self.strict {
+            return deserializer.deserialize_f64(self);
+        }
+        // Integers beyond `u64` are parsed as `f64` like any other number, so check the literal
+        let literal = Box::<RawValue>::deserialize(deserializer)?;
+        let literal = literal.get();
+        let integer = literal.trim_start_matches('-');
+        if !integer.is_empty() && integer.bytes().all(|b| b.is_ascii_digit()) {
+            let exact = integer
+                .parse::<u128>()
+                .is_ok_and(|v| v <= u128::from(MAX_EXACT_INTEGER));
+            if !exact {
+                return Err(de::Error::custom(format_args!(
+                    "negotiated rate {literal} is not exactly representable as a floating-point \
+                    number"
+                )));
+            }
+        }
+        let value = serde_json::from_str::<Value>(literal).map_err(de::Error::custom)?;
+        RateSeed { strict: false }
+            .deserialize(&value)
+            .map_err(de::Error::custom)
+    }
+}
+
+impl<'de> Visitor<'de> for RateSeed {
+    type Value = f64;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a number")
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(v)
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(v as f64)
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(v as f64)
+    }
+}
+
+enum PriceField {
+    Rate,
+    Weight,
+    Other,
+}
+
+/// Deserializes the name of a field of a negotiated price
+struct PriceFieldSeed<'a> {
+    weight_field: Option<&'a str>,
+}
+
+impl<'de> DeserializeSeed<'de> for PriceFieldSeed<'_> {
+    type Value = PriceField;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_identifier(self)
+    }
+}
+
+impl<'de> Visitor<'de> for PriceFieldSeed<'_> {
+    type Value = PriceField;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a field name")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(match v {
+            _ if Some(v) == self.weight_field => PriceField::Weight,
+            "negotiated_rate" => PriceField::Rate,
+            _ => PriceField::Other,
+        })
+    }
+}
+
+/// Options controlling how records are read, filtered and written
+///
+/// These are the command-line options of the tool, which documents each field.
+#[derive(Debug, PartialEq, Serialize, clap::Args)]
+#[command(group(
+    clap::ArgGroup::new("billing_code_filter")
+        .args(["billing_code", "billing_codes_file"])
+        .multiple(true)
+))]
+pub struct ProcessOptions {
+    /// Only average the first N prices of each group of negotiated prices
+    ///
+    /// This biases the average towards the prices listed first.
+    #[arg(long, env = "ETL_MAX_PRICES_PER_GROUP")]
+    pub max_prices_per_group: Option<u64>,
+    /// Only average the first price of each group of negotiated prices, like
+    /// `--max-prices-per-group 1`
+    #[arg(
+        long,
+        env = "ETL_FIRST_PRICE_ONLY",
+        conflicts_with = "max_prices_per_group"
+    )]
+    pub first_price_only: bool,
+    /// Ignore groups of negotiated prices with fewer than N prices, which then contribute nothing
+    /// to the average
+    ///
+    /// Unlike `--min-rate-count`, this drops thin groups rather than whole records.
+    #[arg(long, env = "ETL_MIN_PRICES_PER_GROUP", default_value_t = 0)]
+    pub min_prices_per_group: u64,
+    /// Write one row per negotiated price instead of one row per record with the average
+    ///
+    /// Rate thresholds then apply to each price.
+    #[arg(long, env = "ETL_EXPLODE_PRICES")]
+    pub explode_prices: bool,
+    /// Keep records whatever their rates, only applying `--include-null-rate`
+    #[arg(
+        long,
+        env = "ETL_NO_FILTER",
+        conflicts_with_all = [
+            "max_rate",
+            "min_rate",
+            "min_rate_count",
+            "max_coefficient_of_variation",
+            "rate_epsilon",
+        ],
+    )]
+    pub no_filter: bool,
+    /// Maximum average rate of records to keep
+    #[arg(long, env = "ETL_MAX_RATE", default_value_t = 30.0)]
+    pub max_rate: f64,
+    /// Minimum average rate of records to keep
+    #[arg(long, env = "ETL_MIN_RATE")]
+    pub min_rate: Option<f64>,
+    /// Tolerance of the comparison of average rates with `--max-rate` and `--min-rate`, to keep
+    /// records whose rate is off the threshold only by floating-point error
+    #[arg(long, env = "ETL_RATE_EPSILON", default_value_t = 0.0)]
+    pub rate_epsilon: f64,
+    /// Minimum number of negotiated prices a record must have to be kept
+    #[arg(long, env = "ETL_MIN_RATE_COUNT", default_value_t = 0)]
+    pub min_rate_count: u64,
+    /// Maximum ratio of the standard deviation to the average of the rates of records to keep
+    ///
+    /// Records with fewer than two rates are exempt.
+    #[arg(long, env = "ETL_MAX_COEFFICIENT_OF_VARIATION")]
+    pub max_coefficient_of_variation: Option<f64>,
+    /// Keep records without any negotiated rate, regardless of rate thresholds
+    #[arg(long, env = "ETL_INCLUDE_NULL_RATE")]
+    pub include_null_rate: bool,
+    /// Also write records rejected by the rate thresholds
+    #[arg(long, env = "ETL_INCLUDE_REJECTED")]
+    pub include_rejected: bool,
+    /// Add a status column telling whether each record was kept or why it was rejected
+    #[arg(long, env = "ETL_ANNOTATE_STATUS", requires = "include_rejected")]
+    pub annotate_status: bool,
+    /// Write the number of kept records per billing code, sorted by code, instead of the records
+    #[arg(long, env = "ETL_COUNT_ONLY", conflicts_with = "annotate_status")]
+    pub count_only: bool,
+    /// Write a last CSV row named `TOTAL` with the number of records written as billing code and
+    /// the mean of their average rates
+    #[arg(
+        long,
+        env = "ETL_APPEND_SUMMARY_ROW",
+        conflicts_with_all = ["count_only", "emit_every", "explode_prices", "project"],
+    )]
+    pub append_summary_row: bool,
+    /// Add a rates column listing the negotiated rates each average is computed from, separated
+    /// by semicolons
+    #[arg(
+        long,
+        env = "ETL_WITH_RATES",
+        conflicts_with_all = ["explode_prices", "count_only", "emit_every"],
+    )]
+    pub with_rates: bool,
+    /// Add a rate_frequencies column listing the distinct negotiated rates of each record with
+    /// their number of occurrences, as `rate:count` separated by semicolons
+    #[arg(
+        long,
+        env = "ETL_RATE_FREQUENCIES",
+        alias = "coalesce-rates",
+        conflicts_with_all = ["explode_prices", "count_only", "emit_every"],
+    )]
+    pub rate_frequencies: bool,
+    /// Add a rolling_avg_rate column with the mean of the average rates of the last N kept
+    /// records, including the current one
+    ///
+    /// Records without a rate are left out of the window, and the first records have a partial
+    /// window of the rates kept so far.
+    #[arg(
+        long,
+        env = "ETL_ROLLING_WINDOW",
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with_all = ["explode_prices", "count_only", "emit_every"],
+    )]
+    pub rolling_window: Option<u64>,
+    /// Only write records whose average rate is more than this many standard deviations away from
+    /// the mean of the average rates of all the records kept, reported on stderr
+    ///
+    /// Kept records are held in memory until the whole input is read.
+    #[arg(
+        long,
+        env = "ETL_OUTLIERS",
+        conflicts_with_all = [
+            "explode_prices",
+            "count_only",
+            "emit_every",
+            "include_rejected",
+            "expect",
+        ],
+    )]
+    pub outliers: Option<f64>,
+    /// Assume the input is sorted by `--key`, writing the count of each group as soon as the
+    /// next one starts instead of keeping all counts in memory
+    ///
+    /// An out-of-order record is an error.
+    #[arg(long, env = "ETL_ASSUME_SORTED", requires = "count_only")]
+    pub assume_sorted: bool,
+    /// Comma-separated record fields identifying groups of records, e.g. with `--count-only` or
+    /// `--emit-every`
+    #[arg(
+        long,
+        env = "ETL_KEY",
+        alias = "group-by",
+        value_enum,
+        value_delimiter = ',',
+        default_value = "billing_code"
+    )]
+    pub key: Vec<KeyField>,
+    /// Write a snapshot of the average rate of each group every N input records, instead of the
+    /// records
+    ///
+    /// Each snapshot row holds the snapshot number, the Unix time it was taken at, the group key,
+    /// and the average and number of rates accumulated so far across all records of the group. A
+    /// last snapshot is written at the end of input. Rate thresholds apply to group averages.
+    /// One accumulator is kept in memory per distinct group.
+    #[arg(
+        long,
+        env = "ETL_EMIT_EVERY",
+        conflicts_with_all = ["count_only", "explode_prices", "annotate_status"],
+        value_parser = clap::value_parser!(u64).range(1..),
+    )]
+    pub emit_every: Option<u64>,
+    /// Stop after reading this many input lines (or CSV records), whether or not their records are kept
+    #[arg(long, env = "ETL_MAX_RECORDS")]
+    pub max_records: Option<u64>,
+    /// Fail on a record taking longer than this to aggregate, in seconds or with a unit among
+    /// `ms`, `s`, `m` and `h` (e.g. `500ms`)
+    ///
+    /// A failed record counts as a failed line with `--max-errors`.
+    #[arg(long, env = "ETL_PER_RECORD_TIMEOUT", value_parser = parse_duration)]
+    pub per_record_timeout: Option<Duration>,
+    /// Parse every JSON object concatenated on a line (e.g. `{...}{...}`) as a separate record
+    /// instead of rejecting lines with trailing characters
+    #[arg(long, env = "ETL_MULTI_OBJECT_LINES")]
+    pub multi_object_lines: bool,
+    /// Skip blank JSONL lines and lines starting with `--comment-char`, counting them in
+    /// `--result-file` apart from failed lines
+    #[arg(long, env = "ETL_ALLOW_COMMENTS")]
+    pub allow_comments: bool,
+    /// Skip lines that fail to be read or parsed, with a warning on stderr, aborting once more
+    /// than this many have failed
+    #[arg(long, env = "ETL_MAX_ERRORS")]
+    pub max_errors: Option<u64>,
+    /// Whether non-finite rates are skipped or propagated when computing minimum and maximum
+    /// rates
+    #[arg(long, env = "ETL_NONFINITE_MINMAX", value_enum, default_value_t)]
+    pub nonfinite_minmax: NonFiniteMinMax,
+    /// Handling of negotiated rates of exactly zero
+    #[arg(long, env = "ETL_ZERO_RATE_POLICY", value_enum, default_value_t)]
+    pub zero_rate_policy: ZeroRatePolicy,
+    /// Reject integer negotiated rates above 2^53, which cannot all be represented exactly as
+    /// floating-point numbers, instead of rounding them
+    #[arg(long, env = "ETL_STRICT_NUMBERS")]
+    pub strict_numbers: bool,
+    /// Statistic of the negotiated rates written as the average rate of each record
+    #[arg(long, env = "ETL_AGGREGATION", value_enum, default_value_t)]
+    pub aggregation: Aggregation,
+    /// Field of each negotiated price holding its weight in `--aggregation median`, e.g. a claim
+    /// volume
+    ///
+    /// Prices without a non-negative weight are an error.
+    #[arg(long, env = "ETL_WEIGHT_FIELD")]
+    pub weight_field: Option<String>,
+    /// Decimal places negotiated rates are rounded to before counting them in
+    /// `--aggregation mode`
+    #[arg(long, env = "ETL_MODE_PRECISION")]
+    pub mode_precision: Option<u32>,
+    /// Number of times to retry reading a line after a transient I/O error
+    #[arg(long, env = "ETL_READ_RETRIES", default_value_t = 0)]
+    pub read_retries: u32,
+    /// Suppress all diagnostics on stderr except the error causing an abort
+    #[arg(short, long, env = "ETL_QUIET")]
+    pub quiet: bool,
+    /// Periodically report the number of records read and written on stderr
+    #[arg(long, env = "ETL_PROGRESS", conflicts_with = "quiet")]
+    pub progress: bool,
+    /// Format of progress reports
+    #[arg(
+        long,
+        env = "ETL_PROGRESS_FORMAT",
+        value_enum,
+        default_value_t,
+        requires = "progress"
+    )]
+    pub progress_format: ProgressFormat,
+    /// Report on stderr how many groups of negotiated prices had each number of prices
+    #[arg(long, env = "ETL_GROUP_SIZE_HISTOGRAM", conflicts_with = "quiet")]
+    pub group_size_histogram: bool,
+    /// Report on stderr an estimate of the number of distinct negotiated rates in the input
+    ///
+    /// The estimate uses HyperLogLog, in constant memory, with a standard error of about 0.8%.
+    #[arg(long, env = "ETL_MEASURE_CARDINALITY", conflicts_with = "quiet")]
+    pub measure_cardinality: bool,
+    /// Report on stderr how many input lines failed with each category of error, e.g. missing
+    /// fields
+    ///
+    /// Without `--max-errors`, processing stops at the first error, which is the only one
+    /// reported.
+    #[arg(long, env = "ETL_COLLECT_ERRORS", conflicts_with = "quiet")]
+    pub collect_errors: bool,
+    /// Report on stderr the wall time spent reading, decompressing, parsing, aggregating and
+    /// writing
+    #[arg(long, env = "ETL_PROFILE", conflicts_with = "quiet")]
+    pub profile: bool,
+    /// Name of the top-level field holding the array of negotiated rates
+    #[arg(long, env = "ETL_RATES_FIELD", default_value = "negotiated_rates")]
+    pub rates_field: String,
+    /// Read a record field from an input field with another name, given as `field=input_field`
+    /// (e.g. `name=provider_name`, can be repeated)
+    ///
+    /// The name, billing code and billing code type can be mapped. The input field with the
+    /// default name of a mapped field is then ignored.
+    #[arg(long, env = "ETL_MAP")]
+    pub map: Vec<FieldMapping>,
+    /// Only keep records with this billing code (can be repeated)
+    #[arg(long, env = "ETL_BILLING_CODE", value_delimiter = ',')]
+    pub billing_code: Vec<String>,
+    /// Only keep records with a billing code listed in this file, one per line
+    #[arg(long, env = "ETL_BILLING_CODES_FILE")]
+    pub billing_codes_file: Option<PathBuf>,
+    /// Report on stderr which billing codes listed in this file, one per line, are present in the
+    /// output and which are missing
+    #[arg(
+        long,
+        env = "ETL_EXPECTED_CODES",
+        value_name = "FILE",
+        conflicts_with_all = ["count_only", "emit_every", "quiet"],
+    )]
+    pub expected_codes: Option<PathBuf>,
+    /// Only keep records whose billing code hashes into this shard, given as `index/count` (e.g.
+    /// `2/8`), to split processing across runs
+    ///
+    /// Shards are numbered from 1, and every billing code belongs to exactly one of them.
+    #[arg(long, env = "ETL_SHARD")]
+    pub shard: Option<Shard>,
+    /// Only keep records accepted by the `keep` function of this WebAssembly module (requires the
+    /// `filter-wasm` feature)
+    ///
+    /// The module must export its `memory`, `alloc(len: i32) -> i32`, returning the address of
+    /// `len` bytes the record can be written to, and `keep(ptr: i32, len: i32) -> i32`. Each record
+    /// is written as a UTF-8 JSON object with its `name`, `billing_code` and `avg_rate`, and kept if
+    /// `keep` returns anything but 0. The module can't import anything and its memory and run time
+    /// are limited.
+    #[arg(long, env = "ETL_FILTER_WASM", value_name = "MODULE")]
+    pub filter_wasm: Option<PathBuf>,
+    /// Input field holding the date of a record, as `YYYY-MM-DD`, read by `--after`, `--before`
+    /// and `--require-date`
+    #[arg(long, env = "ETL_DATE_FIELD", default_value = "last_updated_on")]
+    pub date_field: String,
+    /// Only keep records dated strictly after this date, given as `YYYY-MM-DD`
+    #[arg(long, env = "ETL_AFTER")]
+    pub after: Option<NaiveDate>,
+    /// Only keep records dated strictly before this date, given as `YYYY-MM-DD`
+    #[arg(long, env = "ETL_BEFORE")]
+    pub before: Option<NaiveDate>,
+    /// Reject records without a date, which are otherwise kept by `--after` and `--before`
+    #[arg(long, env = "ETL_REQUIRE_DATE")]
+    pub require_date: bool,
+    /// Stop reading input once this many records matching the billing code filter are written
+    ///
+    /// Rejected records written with `--include-rejected` don't count.
+    #[arg(
+        long,
+        env = "ETL_EXPECT",
+        requires = "billing_code_filter",
+        value_parser = clap::value_parser!(u64).range(1..),
+    )]
+    pub expect: Option<u64>,
+    /// Fail if no records were read from the input, rather than if none were kept
+    #[arg(long, env = "ETL_ERROR_ON_EMPTY_INPUT")]
+    pub error_on_empty_input: bool,
+    /// Fail on a record with the billing code of an earlier record, which counts as a failed line
+    /// with `--max-errors`
+    #[arg(long, env = "ETL_ERROR_ON_DUPLICATE_CODE")]
+    pub error_on_duplicate_code: bool,
+    /// Write each kept row with this probability, between 0 and 1
+    ///
+    /// Rows are sampled independently, so the number of rows written is only approximately this
+    /// fraction of the kept rows.
+    #[arg(long, env = "ETL_SAMPLE_RATE")]
+    pub sample_rate: Option<f64>,
+    /// Seed of the random number generator used by `--sample-rate`, for reproducible samples
+    #[arg(long, env = "ETL_SEED", requires = "sample_rate")]
+    pub seed: Option<u64>,
+    /// Comment line to write before the CSV header (can be repeated)
+    #[arg(long, env = "ETL_OUTPUT_COMMENT")]
+    pub output_comment: Vec<String>,
+    /// Character prefixing each comment line
+    #[arg(long, env = "ETL_COMMENT_CHAR", default_value_t = '#')]
+    pub comment_char: char,
+    /// Normalization applied to record names
+    #[arg(long, env = "ETL_NORMALIZE_NAME", value_enum, default_value_t)]
+    pub normalize_name: NameNormalization,
+    /// Normalization applied to billing codes, both of records and of `--billing-code`,
+    /// `--billing-codes-file` and `--expect-range`, before matching them
+    ///
+    /// Written billing codes are not normalized.
+    #[arg(long, env = "ETL_CODE_NORMALIZE", value_enum, default_value_t)]
+    pub code_normalize: CodeNormalization,
+    /// Substitute defaults for missing `name` and `billing_code` fields instead of failing
+    #[arg(long, env = "ETL_DEFAULT_MISSING")]
+    pub default_missing: bool,
+    /// Name of records missing one, with `--default-missing`
+    #[arg(
+        long,
+        env = "ETL_DEFAULT_NAME",
+        default_value = "",
+        requires = "default_missing"
+    )]
+    pub default_name: String,
+    /// Billing code of records missing one, with `--default-missing`
+    #[arg(
+        long,
+        env = "ETL_DEFAULT_BILLING_CODE",
+        default_value = "",
+        requires = "default_missing"
+    )]
+    pub default_billing_code: String,
+    /// Field delimiter of CSV output, a single ASCII character
+    #[arg(
+        long,
+        env = "ETL_DELIMITER_OUT",
+        alias = "delimiter",
+        default_value_t = ','
+    )]
+    pub delimiter_out: char,
+    /// Quoting of fields in CSV output
+    #[arg(long, env = "ETL_CSV_QUOTE", value_enum, default_value_t)]
+    pub csv_quote: CsvQuote,
+    /// Write floating-point numbers in CSV output with a comma as decimal separator
+    ///
+    /// Requires a `--delimiter-out` other than a comma. The rates packed in the rates and
+    /// rate_frequencies columns use it too, their `;` and `:` separators being unchanged.
+    #[arg(long, env = "ETL_DECIMAL_COMMA")]
+    pub decimal_comma: bool,
+    /// Multiply written rates by this factor (e.g. 100 for cents) and write them as integers
+    /// rounded according to `--rounding`
+    ///
+    /// Non-finite and out-of-range scaled rates are written as null.
+    #[arg(long, env = "ETL_SCALE")]
+    pub scale: Option<f64>,
+    /// Rounding of rates scaled with `--scale`, and of prices to the decimal places of
+    /// `--mode-precision`
+    ///
+    /// Rates are otherwise written unrounded.
+    #[arg(
+        long,
+        env = "ETL_ROUNDING",
+        alias = "round-mode",
+        value_enum,
+        default_value_t
+    )]
+    pub rounding: Rounding,
+    /// File to write end-of-run statistics to as JSON
+    ///
+    /// Statistics include the number of records read and written, the number of distinct billing
+    /// codes written, and the distribution of the average rates written.
+    #[arg(long, env = "ETL_STATS_FILE")]
+    pub stats_file: Option<PathBuf>,
+    /// Previous CSV output to compare with, only writing records whose average rate changed from
+    /// that of their billing code in it, with `prev_rate` and `delta` columns
+    ///
+    /// Records with a billing code missing from the baseline are always written. The baseline is
+    /// read as written with the current `--delimiter-out`, `--decimal-comma` and `--scale`.
+    #[arg(
+        long,
+        env = "ETL_BASELINE",
+        conflicts_with_all = ["explode_prices", "count_only", "emit_every"],
+    )]
+    pub baseline: Option<PathBuf>,
+    /// Change of the average rate from `--baseline` a record needs more than to be written
+    #[arg(
+        long,
+        env = "ETL_MIN_DELTA",
+        default_value_t = 0.0,
+        requires = "baseline"
+    )]
+    pub min_delta: f64,
+    /// File to write the number of records read, passed and rejected, and of lines that failed,
+    /// to as JSON
+    ///
+    /// The file is written even if processing fails, e.g. with `--error-on-empty-input`.
+    #[arg(long, env = "ETL_RESULT_FILE")]
+    pub result_file: Option<PathBuf>,
+    /// Fail after processing if a record with the billing code of this `billing_code,min,max` rule
+    /// has an average rate outside the inclusive range (can be repeated)
+    ///
+    /// All violations are reported. Records with other billing codes are not checked.
+    #[arg(long, env = "ETL_EXPECT_RANGE")]
+    pub expect_range: Vec<ExpectedRange>,
+    /// File of `--expect-range` rules, one per line
+    #[arg(long, env = "ETL_EXPECT_RANGES_FILE")]
+    pub expect_ranges_file: Option<PathBuf>,
+    /// Comma-separated input fields to copy to output columns after the record columns
+    ///
+    /// Missing fields are written as empty values. Fields read otherwise, like `name`, cannot be
+    /// passed through. This does not apply to `--count-only` nor `--emit-every`.
+    #[arg(long, env = "ETL_PASSTHROUGH_FIELDS", value_delimiter = ',')]
+    pub passthrough_fields: Vec<String>,
+    /// Write a column with this `name=pointer` projection instead of the name and billing code
+    /// columns (can be repeated)
+    ///
+    /// The pointer (e.g. `/provider/name`) is resolved in each input object, missing values being
+    /// written as empty values. Input objects must still have the fields of a record, see
+    /// `--default-missing`. This does not apply to `--count-only` nor `--emit-every`.
+    #[arg(long, env = "ETL_PROJECT")]
+    pub project: Vec<Projection>,
+    /// Only write these columns, in this order, instead of all the columns computed with the
+    /// other options (e.g. `name,avg_rate`)
+    ///
+    /// Selecting a column that is not computed, e.g. `rate_count` without `--emit-every`, is an
+    /// error.
+    #[arg(long, env = "ETL_COLUMNS", value_delimiter = ',')]
+    pub columns: Vec<String>,
+    /// Stop writing before the first row that would make the output exceed this many bytes,
+    /// with a warning on stderr
+    ///
+    /// The row crossing the limit is dropped, so the output ends at a row boundary. The closing
+    /// bracket of `--format json` is always written and counted within the limit.
+    #[arg(long, env = "ETL_MAX_OUTPUT_BYTES")]
+    pub max_output_bytes: Option<u64>,
+    /// Format of the input
+    ///
+    /// Each CSV record counts as a single rate equal to its average rate, e.g. for
+    /// `--min-rate-count`.
+    #[arg(long, env = "ETL_INPUT_FORMAT", value_enum, default_value_t)]
+    pub input_format: InputFormat,
+    /// Format of the output
+    #[arg(long, env = "ETL_FORMAT", value_enum, default_value_t)]
+    pub format: OutputFormat,
+    /// Indent JSON output, only supported with `--format json`
+    #[arg(long, env = "ETL_PRETTY")]
+    pub pretty: bool,
+    /// Representation of null values, like missing average rates, in JSON output
+    #[arg(long, env = "ETL_JSON_NULL_MODE", value_enum, default_value_t)]
+    pub json_null_mode: JsonNullMode,
+    /// Number of rows per record batch written with `--format arrow`
+    #[arg(
+        long,
+        env = "ETL_ARROW_BATCH_SIZE",
+        default_value_t = 1024,
+        value_parser = clap::value_parser!(u64).range(1..),
+    )]
+    pub arrow_batch_size: u64,
+    /// Maximum number of rows written with `--format table`, further rows being dropped with a
+    /// warning
+    #[arg(long, env = "ETL_TABLE_MAX_ROWS", default_value_t = 1000)]
+    pub table_max_rows: u64,
+}
+
+impl Default for ProcessOptions {
+    /// Returns the defaults of the command-line options
+    fn default() -> Self {
+        Self {
+            max_prices_per_group: None,
+            min_prices_per_group: 0,
+            first_price_only: false,
+            explode_prices: false,
+            no_filter: false,
+            max_rate: 30.0,
+            rate_epsilon: 0.0,
+            min_rate: None,
+            min_rate_count: 0,
+            max_coefficient_of_variation: None,
+            include_null_rate: false,
+            include_rejected: false,
+            annotate_status: false,
+            append_summary_row: false,
+            with_rates: false,
+            rate_frequencies: false,
+            rolling_window: None,
+            outliers: None,
+            count_only: false,
+            assume_sorted: false,
+            key: vec![KeyField::BillingCode],
+            emit_every: None,
+            max_records: None,
+            per_record_timeout: None,
+            multi_object_lines: false,
+            allow_comments: false,
+            max_errors: None,
+            nonfinite_minmax: NonFiniteMinMax::Ignore,
+            zero_rate_policy: ZeroRatePolicy::Include,
+            strict_numbers: false,
+            aggregation: Aggregation::Mean,
+            weight_field: None,
+            mode_precision: None,
+            read_retries: 0,
+            quiet: false,
+            progress: false,
+            progress_format: ProgressFormat::Text,
+            group_size_histogram: false,
+            measure_cardinality: false,
+            collect_errors: false,
+            profile: false,
+            rates_field: "negotiated_rates".into(),
+            map: Vec::new(),
+            billing_code: Vec::new(),
+            billing_codes_file: None,
+            expected_codes: None,
+            shard: None,
+            filter_wasm: None,
+            date_field: "last_updated_on".into(),
+            after: None,
+            before: None,
+            require_date: false,
+            expect: None,
+            error_on_empty_input: false,
+            error_on_duplicate_code: false,
+            sample_rate: None,
+            seed: None,
+            output_comment: Vec::new(),
+            comment_char: '#',
+            normalize_name: NameNormalization::None,
+            code_normalize: CodeNormalization::None,
+            default_missing: false,
+            default_name: String::new(),
+            default_billing_code: String::new(),
+            delimiter_out: ',',
+            csv_quote: CsvQuote::Necessary,
+            decimal_comma: false,
+            scale: None,
+            rounding: Rounding::default(),
+            stats_file: None,
+            baseline: None,
+            min_delta: 0.0,
+            result_file: None,
+            expect_range: Vec::new(),
+            expect_ranges_file: None,
+            passthrough_fields: Vec::new(),
+            project: Vec::new(),
+            columns: Vec::new(),
+            max_output_bytes: None,
+            input_format: InputFormat::Jsonl,
+            format: OutputFormat::Csv,
+            pretty: false,
+            json_null_mode: JsonNullMode::Null,
+            arrow_batch_size: 1024,
+            table_max_rows: 1000,
+        }
+    }
+}
+
+impl ProcessOptions {
+    /// Returns the maximum number of prices averaged in each group of negotiated prices
+    fn prices_per_group(&self) -> Option<u64> {
+        if self.first_price_only {
+            Some(1)
+        } else {
+            self.max_prices_per_group
+        }
+    }
+
+    /// Returns whether records are filtered on their date
+    fn filters_dates(&self) -> bool {
+        self.after.is_some() || self.before.is_some() || self.require_date
+    }
+
+    /// Returns whether a record with `date` is kept by `--after`, `--before` and `--require-date`
+    fn date_matches(&self, date: Option<NaiveDate>) -> bool {
+        match date {
+            None => !self.require_date,
+            Some(date) => {
+                self.after.is_none_or(|after| date > after)
+                    && self.before.is_none_or(|before| date < before)
+            }
+        }
+    }
+
+    /// Returns the name of the input field read as the record field `field`, as set with `--map`
+    fn input_field<'a>(&'a self, field: &'a str) -> &'a str {
+        self.map
+            .iter()
+            .rev()
+            .find(|mapping| mapping.field == field)
+            .map_or(field, |mapping| mapping.input.as_str())
+    }
+
+    /// Returns the names of the columns written with these options
+    pub fn columns(&self) -> Vec<String> {
+        if self.columns.is_empty() {
+            self.computed_columns()
+        } else {
+            self.columns.clone()
+        }
+    }
+
+    /// Returns an error if `--columns` selects a column that is not computed or selects it twice
+    fn check_columns(&self) -> anyhow::Result<()> {
+        let computed = self.computed_columns();
+        let mut selected = HashSet::new();
+        for column in &self.columns {
+            if !computed.contains(column) {
+                anyhow::bail!(
+                    "column {column} is not computed with these options, expected one of: {}",
+                    computed.join(", ")
+                );
+            }
+            if !selected.insert(column) {
+                anyhow::bail!("column {column} is selected more than once");
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the fields of `row` selected by `--columns`, in their order
+    fn select_columns(
+        &self,
+        mut row: serde_json::Map<String, Value>,
+    ) -> serde_json::Map<String, Value> {
+        if self.columns.is_empty() {
+            return row;
+        }
+        self.columns
+            .iter()
+            .map(|column| (column.clone(), row.remove(column).unwrap_or_default()))
+            .collect()
+    }
+
+    /// Returns the names of all the columns computed with these options
+    fn computed_columns(&self) -> Vec<String> {
+        let key = self
+            .key
+            .iter()
+            .filter_map(|field| field.to_possible_value())
+            .map(|value| value.get_name().to_owned());
+        let columns: Vec<String> = if self.count_only {
+            key.chain(["count".into()]).collect()
+        } else if self.emit_every.is_some() {
+            ["snapshot".into(), "timestamp".into()]
+                .into_iter()
+                .chain(key)
+                .chain(["avg_rate".into(), "rate_count".into()])
+                .collect()
+        } else {
+            let rate = if self.explode_prices {
+                "negotiated_rate"
+            } else {
+                "avg_rate"
+            };
+            let identity: Vec<String> = if self.project.is_empty() {
+                vec!["name".into(), "billing_code".into()]
+            } else {
+                self.project.iter().map(|p| p.name.clone()).collect()
+            };
+            let change = self
+                .baseline
+                .iter()
+                .flat_map(|_| ["prev_rate".into(), "delta".into()]);
+            identity
+                .into_iter()
+                .chain([rate.into()])
+                .chain(self.passthrough_fields.iter().cloned())
+                .chain(change)
+                .chain(self.with_rates.then(|| "rates".into()))
+                .chain(self.rate_frequencies.then(|| "rate_frequencies".into()))
+                .chain(self.rolling_window.map(|_| "rolling_avg_rate".into()))
+                .collect()
+        };
+        if self.annotate_status {
+            columns.into_iter().chain(["status".into()]).collect()
+        } else {
+            columns
+        }
+    }
+
+    /// Returns the value to write for `rate`, scaled to an integer with `--scale`
+    fn rate_value(&self, rate: Option<f64>) -> Value {
+        let Some(scale) = self.scale else {
+            return rate.into();
+        };
+        rate.map(|rate| self.rounding.apply(rate * scale))
+            .filter(|&rate| rate.is_finite() && rate.abs() < i64::MAX as f64)
+            .map(|rate| rate as i64)
+            .into()
+    }
+
+    /// Returns how `rate` is written in a column packing several rates, with the decimal
+    /// separator of `--decimal-comma`
+    fn packed_rate(&self, rate: f64) -> String {
+        let rate = self.rate_value(Some(rate)).to_string();
+        if self.decimal_comma {
+            rate.replace('.', ",")
+        } else {
+            rate
+        }
+    }
+
+    /// Returns whether a record with `avg_rate`, computed from the rates accumulated in `rate`, is
+    /// kept or why it is rejected
+    ///
+    /// `avg_rate` is a single price instead of the average with `--explode-prices`.
+    pub fn disposition(&self, avg_rate: Option<f64>, rate: &AccumulatedRate) -> Disposition {
+        let too_variable = || {
+            self.max_coefficient_of_variation
+                .zip(rate.coefficient_of_variation())
+                .is_some_and(|(max, cv)| cv.abs() > max)
+        };
+        match avg_rate {
+            None if self.include_null_rate => Disposition::Kept,
+            None => Disposition::RejectedNull,
+            Some(_) if self.no_filter => Disposition::Kept,
+            Some(_) if rate.count < self.min_rate_count => Disposition::RejectedLowCount,
+            Some(_) if too_variable() => Disposition::RejectedVariation,
+            Some(r) if self.min_rate.is_some_and(|min| r < min - self.rate_epsilon) => {
+                Disposition::RejectedLow
+            }
+            Some(r) if r <= self.max_rate + self.rate_epsilon => Disposition::Kept,
+            Some(_) => Disposition::RejectedHigh,
+        }
+    }
+}
+
+/// Outcome of filtering a record on its average rate
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Disposition {
+    Kept,
+    RejectedHigh,
+    RejectedLow,
+    RejectedNull,
+    RejectedLowCount,
+    RejectedVariation,
+}
+
+/// Minimum delay between progress reports
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Delay before the first retry of a transient read error, doubled on each subsequent attempt
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+
+/// Counts of records seen while processing
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    /// Records read from the input
+    pub read: u64,
+    /// Records written to the output
+    pub written: u64,
+}
+
+/// Reads JSONL records from `input` and writes those kept as CSV to `output`
+pub fn process<I, O>(input: I, output: O, options: &ProcessOptions) -> anyhow::Result<Summary>
+where
+    I: BufRead,
+    O: Write,
+{
+    process_with(
+        input,
+        RowWriter::new(output, None, Resume::Start, options)?,
+        None,
+        None,
+        options,
+    )
+}
+
+/// Like [`process`], but also returns the counts of records of each of `inputs`
+///
+/// Records written while finishing the output, like `--count-only` counts, are attributed to the
+/// last input.
+pub fn process_inputs<N, I, O>(
+    mut inputs: Inputs<N, I>,
+    output: O,
+    options: &ProcessOptions,
+) -> anyhow::Result<Vec<InputSummary>>
+where
+    N: Iterator<Item = io::Result<(String, I)>>,
+    I: BufRead,
+    O: Write,
+{
+    if options.input_format == InputFormat::Csv {
+        // The CSV reader buffers input, so the input of each record is not known
+        anyhow::bail!("counts per input are not supported with --input-format csv");
+    }
+    let mut tally = Tally {
+        names: inputs.names.clone(),
+        inputs: Vec::new(),
+        input: 0,
+        last: (0, 0, 0),
+    };
+    let output = RowWriter::new(output, None, Resume::Start, options)?;
+    process_with(&mut inputs, output, None, Some(&mut tally), options)?;
+    inputs.open_remaining().context("failed to read input")?;
+    tally.add_opened();
+    Ok(tally.inputs)
+}
+
+/// Counts of records read from one input
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct InputSummary {
+    /// Name of the input
+    pub input: String,
+    /// Records read from the input
+    pub read: u64,
+    /// Records from the input written to the output
+    pub written: u64,
+    /// Lines of the input skipped because they failed to be read or parsed
+    pub failed: u64,
+}
+
+/// Reader of named inputs one after the other, as if they were a single input
+///
+/// A newline is inserted at the end of an input not ending with one so that no line spans inputs.
+pub struct Inputs<N, I> {
+    /// Inputs not opened yet
+    pending: std::iter::Fuse<N>,
+    /// Input being read, `None` before the first one and after each one is read to the end
+    input: Option<I>,
+    /// Names of the inputs opened so far, shared with the [`Tally`] of their records
+    names: Rc<RefCell<Vec<String>>>,
+    /// Whether the last byte consumed from the current input ended a line, or nothing was
+    /// consumed from it
+    at_line_start: bool,
+    newline_pending: bool,
+}
+
+impl<I> Inputs<std::vec::IntoIter<io::Result<(String, I)>>, I> {
+    /// Returns a reader of `inputs`, each paired with its name
+    pub fn new(inputs: Vec<(String, I)>) -> Self {
+        Self::streamed(inputs.into_iter().map(Ok).collect::<Vec<_>>().into_iter())
+    }
+}
+
+impl<N, I> Inputs<N, I>
+where
+    N: Iterator<Item = io::Result<(String, I)>>,
+{
+    /// Returns a reader of the inputs yielded by `inputs`, each paired with its name
+    ///
+    /// The next input is only taken from `inputs` once the previous one is read to the end, so
+    /// that inputs can be read from a stream, like the members of an archive.
+    pub fn streamed(inputs: N) -> Self {
+        Self {
+            pending: inputs.fuse(),
+            input: None,
+            names: Rc::default(),
+            at_line_start: true,
+            newline_pending: false,
+        }
+    }
+
+    /// Opens the inputs left unread, e.g. once enough records were written, so that their names
+    /// are known
+    fn open_remaining(&mut self) -> io::Result<()> {
+        self.input = None;
+        for input in &mut self.pending {
+            let (name, _) = input?;
+            self.names.borrow_mut().push(name);
+        }
+        Ok(())
+    }
+}
+
+impl<N, I> Read for Inputs<N, I>
+where
+    N: Iterator<Item = io::Result<(String, I)>>,
+    I: BufRead,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<N, I> BufRead for Inputs<N, I>
+where
+    N: Iterator<Item = io::Result<(String, I)>>,
+    I: BufRead,
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        loop {
+            if self.newline_pending {
+                return Ok(b"\n");
+            }
+            let Some(input) = &mut self.input else {
+                let Some((name, input)) = self.pending.next().transpose()? else {
+                    return Ok(&[]);
+                };
+                self.names.borrow_mut().push(name);
+                self.input = Some(input);
+                continue;
+            };
+            if !input.fill_buf()?.is_empty() {
+                break;
+            }
+            if self.at_line_start {
+                self.input = None;
+            } else {
+                self.newline_pending = true;
+            }
+        }
+        // Filling again returns the buffer just filled
+        match &mut self.input {
+            Some(input) => input.fill_buf(),
+            None => Ok(&[]),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if amt == 0 {
+            return;
+        }
+        if self.newline_pending {
+            // Stay on the current input until the line is processed
+            self.newline_pending = false;
+            self.at_line_start = true;
+            return;
+        }
+        if let Some(input) = &mut self.input {
+            if let Ok(buf) = input.fill_buf() {
+                self.at_line_start = buf.get(amt - 1) == Some(&b'\n');
+            }
+            input.consume(amt);
+        }
+    }
+}
+
+/// Attribution of records to the [`Inputs`] they come from
+struct Tally {
+    /// Names of the inputs opened so far
+    names: Rc<RefCell<Vec<String>>>,
+    inputs: Vec<InputSummary>,
+    /// Input of the last line read
+    input: usize,
+    /// Records read, written and failed at the last update
+    last: (u64, u64, u64),
+}
+
+impl Tally {
+    /// Attributes the records counted since the last update to the input of the last line read,
+    /// then starts counting for the input being read
+    fn update(&mut self, summary: &Summary, failed: u64) {
+        if let Some(input) = self.inputs.get_mut(self.input) {
+            input.read += summary.read - self.last.0;
+            input.written += summary.written - self.last.1;
+            input.failed += failed - self.last.2;
+        }
+        self.last = (summary.read, summary.written, failed);
+        self.add_opened();
+        self.input = self.inputs.len().saturating_sub(1);
+    }
+
+    /// Starts counting records for the inputs opened since the last update
+    fn add_opened(&mut self) {
+        let names = self.names.borrow();
+        let opened = names[self.inputs.len()..].iter().map(|name| InputSummary {
+            input: name.clone(),
+            ..InputSummary::default()
+        });
+        self.inputs.extend(opened);
+    }
+}
+
+/// Like [`process`], but processes the inputs named by `names` in parallel on up to `jobs`
+/// threads, each input being opened with `open`, given its index in `names`, and processed on
+/// its own
+///
+/// Outputs are written in the order of `names`, so only CSV and newline-delimited JSON output are
+/// supported. Options applying to the whole input, like `--max-errors`, apply to each input
+/// separately. Processing stops at the first input failing, unless `--max-errors` is set, in which
+/// case the other inputs are still processed and written, and the failures all reported at the
+/// end.
+pub fn process_parallel<O, F, I>(
+    names: &[String],
+    open: F,
+    output: O,
+    jobs: NonZeroUsize,
+    options: &ProcessOptions,
+) -> anyhow::Result<Summary>
+where
+    O: Write,
+    F: Fn(usize) -> anyhow::Result<I> + Sync,
+    I: BufRead,
+{
+    let next = AtomicUsize::new(0);
+    let next_input = || {
+        let i = next.fetch_add(1, atomic::Ordering::Relaxed);
+        (i < names.len()).then(|| (i, open(i)))
+    };
+    process_parallel_with(names, next_input, output, jobs, options)
+}
+
+/// Size of the chunks inputs are read in by [`process_parallel_prefetched`]
+const PREFETCH_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of chunks read ahead of the processing of each input by [`process_parallel_prefetched`]
+const PREFETCH_CHUNKS: usize = 16;
+
+/// Like [`process_parallel`], but reads inputs on up to `io_concurrency` threads of their own, so
+/// that reading inputs, e.g. from slow storage, overlaps with processing others
+///
+/// `read` is called with the index of each input in `names` on a reading thread, and `open` turns
+/// the bytes read from it into records to process on a processing thread, e.g. decompressing them.
+/// Up to [`PREFETCH_CHUNKS`] chunks of each input are read ahead of its processing, and about as
+/// many inputs as there are reading threads are read ahead of the processing threads.
+pub fn process_parallel_prefetched<O, R, S, F, I>(
+    names: &[String],
+    read: R,
+    open: F,
+    output: O,
+    jobs: NonZeroUsize,
+    io_concurrency: NonZeroUsize,
+    options: &ProcessOptions,
+) -> anyhow::Result<Summary>
+where
+    O: Write,
+    R: Fn(usize) -> anyhow::Result<S> + Sync,
+    S: Read,
+    F: Fn(usize, Prefetched) -> anyhow::Result<I> + Sync,
+    I: BufRead,
+{
+    let next = AtomicUsize::new(0);
+    let prefetch = |inputs: mpsc::SyncSender<_>| {
+        loop {
+            let i = next.fetch_add(1, atomic::Ordering::Relaxed);
+            if i >= names.len() {
+                break;
+            }
+            let (chunks, received) = mpsc::sync_channel(PREFETCH_CHUNKS);
+            let input = read(i).map(|input| (input, Prefetched::new(received)));
+            let (mut input, prefetched) = match input {
+                Ok((input, prefetched)) => (input, Ok(prefetched)),
+                Err(e) => {
+                    let _ = inputs.send((i, Err(e)));
+                    continue;
+                }
+            };
+            // Processing has stopped once inputs are no longer received
+            if inputs.send((i, prefetched)).is_err() {
+                break;
+            }
+            read_chunks(&mut input, &chunks);
+        }
+    };
+    thread::scope(|scope| {
+        let (sender, inputs) = mpsc::sync_channel(io_concurrency.get());
+        for _ in 0..io_concurrency.get().min(names.len()) {
+            let sender = sender.clone();
+            scope.spawn(|| prefetch(sender));
+        }
+        drop(sender);
+        // Dropped before waiting for the reading threads, so that they stop if processing fails
+        let inputs = Mutex::new(inputs);
+        let next_input = || {
+            let (i, input) = inputs.lock().ok()?.recv().ok()?;
+            Some((i, input.and_then(|input| open(i, input))))
+        };
+        process_parallel_with(names, next_input, output, jobs, options)
+    })
+}
+
+/// Sends the chunks read from `input` to `chunks` until the end of `input`, an error, or `chunks`
+/// being disconnected
+fn read_chunks<S: Read>(input: &mut S, chunks: &mpsc::SyncSender<io::Result<Vec<u8>>>) {
+    loop {
+        let mut chunk = vec![0; PREFETCH_CHUNK_SIZE];
+        let chunk = match input.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => {
+                chunk.truncate(n);
+                Ok(chunk)
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => Err(e),
+        };
+        let failed = chunk.is_err();
+        if chunks.send(chunk).is_err() || failed {
+            break;
+        }
+    }
+}
+
+/// Input read ahead of its processing by [`process_parallel_prefetched`]
+pub struct Prefetched {
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    /// Position of the next byte to read in `chunk`
+    position: usize,
+}
+
+impl Prefetched {
+    fn new(chunks: mpsc::Receiver<io::Result<Vec<u8>>>) -> Self {
+        Self {
+            chunks,
+            chunk: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl Read for Prefetched {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => self.chunk = chunk?,
+                // The whole input was read
+                Err(mpsc::RecvError) => return Ok(0),
+            }
+            self.position = 0;
+        }
+        let n = buf.len().min(self.chunk.len() - self.position);
+        buf[..n].copy_from_slice(&self.chunk[self.position..][..n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// Processes the inputs returned by `next_input`, along with their index in `names`, like
+/// [`process_parallel`]
+fn process_parallel_with<O, N, I>(
+    names: &[String],
+    next_input: N,
+    mut output: O,
+    jobs: NonZeroUsize,
+    options: &ProcessOptions,
+) -> anyhow::Result<Summary>
+where
+    O: Write,
+    N: Fn() -> Option<(usize, anyhow::Result<I>)> + Sync,
+    I: BufRead,
+{
+    if !matches!(
+        options.format,
+        OutputFormat::Csv | OutputFormat::Ndjson | OutputFormat::EnrichedJson
+    ) {
+        anyhow::bail!("parallel processing requires --format csv, ndjson or enriched-json");
+    }
+    // Write the comments, each input then being processed as the continuation of the output
+    Sink::new(&mut output, Resume::Start, options)?.finish()?;
+    let failed = AtomicBool::new(false);
+    // With `--max-errors`, an input failing doesn't prevent writing the others
+    let keep_going = options.max_errors.is_some();
+    let work = |parts: mpsc::Sender<_>| {
+        while !failed.load(atomic::Ordering::Relaxed) {
+            let Some((i, input)) = next_input() else {
+                break;
+            };
+            let name = &names[i];
+            let part = input
+                .and_then(|input| {
+                    let mut part = Vec::new();
+                    let output = RowWriter::new(&mut part, None, Resume::AfterHeader, options)?;
+                    let summary = process_with(input, output, None, None, options)?;
+                    Ok((part, summary))
+                })
+                .with_context(|| format!("failed to process {name}"));
+            failed.fetch_or(part.is_err() && !keep_going, atomic::Ordering::Relaxed);
+            if parts.send((i, part)).is_err() {
+                break;
+            }
+        }
+    };
+    thread::scope(|scope| {
+        let (sender, parts) = mpsc::channel();
+        for _ in 0..jobs.get().min(names.len()) {
+            let sender = sender.clone();
+            scope.spawn(|| work(sender));
+        }
+        drop(sender);
+        let mut pending = BTreeMap::new();
+        let mut written = 0;
+        let mut has_header = false;
+        let mut summary = Summary::default();
+        let mut failures = Vec::new();
+        for (i, part) in parts {
+            pending.insert(i, part);
+            // Write the parts that are next in order
+            while let Some(part) = pending.remove(&written) {
+                written += 1;
+                let (part, part_summary) = match part {
+                    Ok(part) => part,
+                    Err(e) if keep_going => {
+                        failures.push(format!("{e:#}"));
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                if options.format == OutputFormat::Csv && !part.is_empty() && !has_header {
+                    let mut header = csv_writer_builder(options)?.from_writer(&mut output);
+                    header
+                        .write_record(options.columns())
+                        .and_then(|()| header.flush().map_err(Into::into))
+                        .context("failed to write header")?;
+                    has_header = true;
+                }
+                output.write_all(&part).context("failed to write output")?;
+                summary.read += part_summary.read;
+                summary.written += part_summary.written;
+            }
+        }
+        output.flush().context("failed to write output")?;
+        if !failures.is_empty() {
+            anyhow::bail!(
+                "{} of {} inputs failed: {}",
+                failures.len(),
+                names.len(),
+                failures.join("; ")
+            );
+        }
+        Ok(summary)
+    })
+}
+
+/// Like [`process`], but splits output across the writers returned by `open`
+///
+/// `open` is called with the index of each part, starting at 0. A new part is started once the
+/// current one reaches `max_bytes`, so parts end at a record boundary and may exceed `max_bytes`
+/// by up to one record. Comments and header are repeated in each part.
+pub fn process_split<I, O, F>(
+    input: I,
+    max_bytes: u64,
+    mut open: F,
+    options: &ProcessOptions,
+) -> anyhow::Result<Summary>
+where
+    I: BufRead,
+    O: Write,
+    F: FnMut(u64) -> anyhow::Result<O>,
+{
+    let first = open(0)?;
+    let split = Split {
+        max_bytes,
+        open: Box::new(open),
+        part: 0,
+    };
+    let output = RowWriter::new(first, Some(split), Resume::Start, options)?;
+    process_with(input, output, None, None, options)
+}
+
+/// Like [`process`], but then reads back the CSV written to `output` from `written` and fails if
+/// it does not parse into the same fields as were written
+///
+/// Written rows are held in memory until they are verified.
+pub fn process_verified<I, O, R, F>(
+    input: I,
+    output: O,
+    written: F,
+    options: &ProcessOptions,
+) -> anyhow::Result<Summary>
+where
+    I: BufRead,
+    O: Write,
+    R: BufRead,
+    F: FnOnce() -> anyhow::Result<R>,
+{
+    let mut rows = Vec::new();
+    let output = RowWriter::new(output, None, Resume::Start, options)?.recording(&mut rows)?;
+    let summary = process_with(input, output, None, None, options)?;
+    verify_csv(written()?, &rows, options)?;
+    Ok(summary)
+}
+
+/// Fails if the CSV read from `input` does not have the fields of `rows`, after comment lines
+fn verify_csv<R>(mut input: R, rows: &[Vec<Value>], options: &ProcessOptions) -> anyhow::Result<()>
+where
+    R: BufRead,
+{
+    let context = "failed to read back output";
+    let comments = options
+        .output_comment
+        .iter()
+        .flat_map(|c| c.lines())
+        .count() as u64;
+    for _ in 0..comments {
+        input.read_line(&mut String::new()).context(context)?;
+    }
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(output_delimiter(options)?)
+        .from_reader(input);
+    let mut read = reader.records();
+    for expected in rows {
+        let Some(fields) = read.next().transpose().context(context)? else {
+            anyhow::bail!("output does not round-trip: it ends before {expected:?}");
+        };
+        let matches = fields.len() == expected.len()
+            && fields
+                .iter()
+                .zip(expected)
+                .all(|(field, expected)| field_matches(field, expected, options.decimal_comma));
+        if !matches {
+            let line = comments + fields.position().map_or(0, |p| p.line());
+            anyhow::bail!(
+                "output does not round-trip: line {line} reads back as {:?} instead of {expected:?}",
+                fields.iter().collect::<Vec<_>>()
+            );
+        }
+    }
+    if let Some(fields) = read.next().transpose().context(context)? {
+        anyhow::bail!("output does not round-trip: {fields:?} was not written");
+    }
+    Ok(())
+}
+
+/// Returns whether `field` read back from CSV output is `expected`, numbers being compared by
+/// value
+fn field_matches(field: &str, expected: &Value, decimal_comma: bool) -> bool {
+    match expected {
+        Value::Null => field.is_empty(),
+        Value::String(s) => field == s,
+        Value::Number(n) if n.is_f64() => {
+            let field = if decimal_comma {
+                field.replace(',', ".")
+            } else {
+                field.to_owned()
+            };
+            field.parse::<f64>().ok() == n.as_f64()
+        }
+        Value::Number(n) => field.parse::<serde_json::Number>().ok().as_ref() == Some(n),
+        expected => serde_json::from_str::<Value>(field).ok().as_ref() == Some(expected),
+    }
+}
+
+/// Writes the columns [`process`] writes with `options`, as a CSV header or a JSON array of names
+pub fn write_schema<O>(mut output: O, options: &ProcessOptions) -> anyhow::Result<()>
+where
+    O: Write,
+{
+    options.check_columns()?;
+    let columns = options.columns();
+    if options.format == OutputFormat::Csv {
+        let mut output = csv_writer_builder(options)?.from_writer(output);
+        output
+            .write_record(&columns)
+            .context("failed to write header")?;
+        output.flush()?;
+    } else {
+        serde_json::to_writer(&mut output, &columns).context("failed to write schema")?;
+        writeln!(output)?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+/// Writes the rows of the CSV `inputs`, e.g. outputs of runs over several `--shard`s, one input
+/// after the other to `output`, under a single header, and returns the number of rows written
+///
+/// Every input must have the same header, except empty inputs, which are skipped as no rows were
+/// written to them.
+pub fn combine_csv<I, O>(
+    inputs: Vec<(String, I)>,
+    output: O,
+    options: &ProcessOptions,
+) -> anyhow::Result<u64>
+where
+    I: Read,
+    O: Write,
+{
+    let mut output = csv_writer_builder(options)?.from_writer(output);
+    let mut first: Option<(String, csv::StringRecord)> = None;
+    let mut rows = 0;
+    for (name, input) in inputs {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(output_delimiter(options)?)
+            .comment(u8::try_from(options.comment_char).ok())
+            .from_reader(input);
+        let header = reader
+            .headers()
+            .with_context(|| format!("failed to read {name}"))?
+            .clone();
+        if header.is_empty() {
+            continue;
+        }
+        match &first {
+            Some((first_name, first_header)) if *first_header != header => anyhow::bail!(
+                "header of {name} ({}) differs from that of {first_name} ({})",
+                header.iter().collect::<Vec<_>>().join(","),
+                first_header.iter().collect::<Vec<_>>().join(","),
+            ),
+            Some(_) => {}
+            None => {
+                output
+                    .write_record(&header)
+                    .context("failed to write header")?;
+                first = Some((name.clone(), header));
+            }
+        }
+        for row in reader.records() {
+            let row = row.with_context(|| format!("failed to read {name}"))?;
+            output
+                .write_record(&row)
+                .context("failed to write record")?;
+            rows += 1;
+        }
+    }
+    output.flush().context("failed to write output")?;
+    Ok(rows)
+}
+
+/// Writes `options`, as resolved from the command line, environment and defaults, as JSON
+pub fn write_config<O>(mut output: O, options: &ProcessOptions) -> anyhow::Result<()>
+where
+    O: Write,
+{
+    serde_json::to_writer_pretty(&mut output, options).context("failed to write config")?;
+    writeln!(output)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Findings of [`probe`] about the fields of the first lines of an input
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ProbeReport {
+    /// Lines read
+    pub lines: u64,
+    /// Records parsed from the lines read
+    pub records: u64,
+    /// Number of objects with each top-level field
+    pub fields: BTreeMap<String, u64>,
+    /// Number of negotiated prices with each field
+    pub price_fields: BTreeMap<String, u64>,
+    /// Error of each line that could not be parsed into records, by line number
+    pub errors: BTreeMap<u64, String>,
+}
+
+impl ProbeReport {
+    /// Formats the report, one count or error per line
+    pub fn to_text(&self) -> String {
+        let mut text = format!("lines: {}\nrecords: {}\n", self.lines, self.records);
+        let fields = [
+            ("fields: objects", &self.fields),
+            ("price fields: prices", &self.price_fields),
+        ];
+        for (title, fields) in fields {
+            text += &format!("{title}\n");
+            for (field, count) in fields {
+                text += &format!("  {field}: {count}\n");
+            }
+        }
+        for (line, error) in &self.errors {
+            text += &format!("line {line}: {error}\n");
+        }
+        text
+    }
+}
+
+/// Parses the first `max_lines` lines of JSONL `input` into records and reports the fields found
+/// in them, to discover the layout of an unfamiliar input
+pub fn probe<I>(input: I, max_lines: u64, options: &ProcessOptions) -> anyhow::Result<ProbeReport>
+where
+    I: BufRead,
+{
+    if options.input_format != InputFormat::Jsonl {
+        anyhow::bail!("--probe requires --input-format jsonl");
+    }
+    let mut report = ProbeReport::default();
+    let count = |counts: &mut BTreeMap<String, u64>, object: &Value| {
+        for field in object.as_object().into_iter().flat_map(|o| o.keys()) {
+            *counts.entry(field.clone()).or_default() += 1;
+        }
+    };
+    for line in lines(input, options).take(max_lines.try_into().unwrap_or(usize::MAX)) {
+        let line = line.context("failed to read line")?;
+        report.lines += 1;
+        if SkippedLine::of(&line, options).is_some() {
+            continue;
+        }
+        let objects = serde_json::Deserializer::from_str(&line).into_iter::<Value>();
+        for object in objects.map_while(Result::ok) {
+            count(&mut report.fields, &object);
+            let rates = object.get(&options.rates_field).and_then(Value::as_array);
+            let prices = rates
+                .into_iter()
+                .flatten()
+                .filter_map(|rate| rate.get("negotiated_prices")?.as_array())
+                .flatten();
+            for price in prices {
+                count(&mut report.price_fields, price);
+            }
+        }
+        match parse_records(&line, options) {
+            Ok(records) => report.records += records.len() as u64,
+            Err(e) => {
+                report.errors.insert(report.lines, e.to_string());
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Position in the input and output of a run, recorded by [`process_checkpointed`] to resume it
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Checkpoint {
+    /// Bytes of input processed
+    pub input_offset: u64,
+    /// Bytes of output written for the processed input
+    pub output_offset: u64,
+}
+
+impl Checkpoint {
+    /// Reads a checkpoint from the file at `p`
+    pub fn read(p: &Path) -> anyhow::Result<Self> {
+        let contents =
+            fs::read_to_string(p).with_context(|| format!("failed to read {}", p.display()))?;
+        serde_json::from_str(&contents)
+            .with_context(|| format!("invalid checkpoint {}", p.display()))
+    }
+
+    /// Writes this checkpoint to the file at `p`, atomically replacing any previous one
+    fn write(&self, p: &Path) -> anyhow::Result<()> {
+        let mut temp = p.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        write_json_file(&temp, &serde_json::to_value(self)?)
+            .and_then(|()| Ok(fs::rename(&temp, p)?))
+            .with_context(|| format!("failed to write checkpoint {}", p.display()))
+    }
+}
+
+/// Like [`process`], but periodically records in `checkpoint` how much input was processed and
+/// how much output was written for it
+///
+/// `input` and `output` continue from `start`, where a previous run stopped, or from the
+/// beginning if `start` is the default. The caller is responsible for positioning them. Output
+/// is flushed before each checkpoint so that it is never behind the checkpoint. Resuming is not
+/// supported with JSON array output nor with output aggregating records, like `--count-only`.
+pub fn process_checkpointed<I, O>(
+    input: I,
+    output: O,
+    checkpoint: &Path,
+    start: Checkpoint,
+    options: &ProcessOptions,
+) -> anyhow::Result<Summary>
+where
+    I: BufRead,
+    O: Write,
+{
+    if options.count_only || options.emit_every.is_some() {
+        anyhow::bail!("checkpoints are not supported with --count-only or --emit-every");
+    }
+    if options.input_format == InputFormat::Csv {
+        // The CSV reader buffers input, so the offset of the last record is not known
+        anyhow::bail!("checkpoints are not supported with --input-format csv");
+    }
+    let offset = Rc::new(Cell::new(start.input_offset));
+    let input = OffsetReader {
+        inner: input,
+        offset: offset.clone(),
+    };
+    let resume = if start.output_offset == 0 {
+        Resume::Start
+    } else if start.output_offset > preamble_len(options) {
+        Resume::AfterHeader
+    } else {
+        // The checkpoint was taken before the first row, and its header
+        Resume::AfterPreamble
+    };
+    let output = RowWriter::new(output, None, resume, options)?;
+    let checkpointer = Checkpointer {
+        path: checkpoint,
+        line: 0,
+        line_end: start.input_offset,
+        start,
+        input_offset: offset,
+        last: Instant::now(),
+        interval: CHECKPOINT_INTERVAL,
+    };
+    process_with(input, output, Some(checkpointer), None, options)
+}
+
+/// Delay between checkpoints
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Periodic writer of checkpoints
+struct Checkpointer<'a> {
+    path: &'a Path,
+    start: Checkpoint,
+    /// Offset in the input of the next line to read
+    input_offset: Rc<Cell<u64>>,
+    /// Line of the last record read, and offset in the input of its end
+    line: usize,
+    line_end: u64,
+    last: Instant,
+    interval: Duration,
+}
+
+impl Checkpointer<'_> {
+    /// Writes a checkpoint if enough time elapsed since the last one, before processing a record
+    /// read from `line`
+    ///
+    /// Checkpoints are only written once all the records of a line are processed, as a line can
+    /// hold several objects with `--multi-object-lines`.
+    fn update<W: Write>(
+        &mut self,
+        line: usize,
+        output: &mut RowWriter<'_, W>,
+    ) -> anyhow::Result<()> {
+        let line_start = std::mem::replace(&mut self.line_end, self.input_offset.get());
+        if line == self.line {
+            return Ok(());
+        }
+        self.line = line;
+        let now = Instant::now();
+        if now.duration_since(self.last) < self.interval {
+            return Ok(());
+        }
+        self.last = now;
+        output.output.flush()?;
+        self.write(line_start, output.output.bytes_written())
+    }
+
+    /// Writes a checkpoint after all the input was processed and `output_bytes` were written in
+    /// this run
+    fn checkpoint(&self, output_bytes: u64) -> anyhow::Result<()> {
+        self.write(self.input_offset.get(), output_bytes)
+    }
+
+    fn write(&self, input_offset: u64, output_bytes: u64) -> anyhow::Result<()> {
+        Checkpoint {
+            input_offset,
+            output_offset: self.start.output_offset + output_bytes,
+        }
+        .write(self.path)
+    }
+}
+
+/// Reader wrapper tracking the offset of the consumed bytes in a shared counter
+struct OffsetReader<R> {
+    inner: R,
+    offset: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for OffsetReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.offset.set(self.offset.get() + n as u64);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for OffsetReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt);
+        self.offset.set(self.offset.get() + amt as u64);
+    }
+}
+
+/// Counts of records written to `--result-file`, whether processing succeeds or not
+#[derive(Debug, Default, Serialize)]
+struct RunResult {
+    /// Records read from the input
+    read: u64,
+    /// Records that passed the billing code filter and rate thresholds
+    passed: u64,
+    /// Records that did not pass them
+    rejected: u64,
+    /// Input lines that failed to be read or parsed
+    errors: u64,
+    /// Blank and comment lines skipped with `--allow-comments`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blank_lines: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment_lines: Option<u64>,
+}
+
+/// Kind of input line skipped with `--allow-comments`
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SkippedLine {
+    Blank,
+    Comment,
+}
+
+impl SkippedLine {
+    /// Returns how `line` is skipped with `options`, `None` if it is parsed
+    fn of(line: &str, options: &ProcessOptions) -> Option<Self> {
+        if !options.allow_comments {
+            return None;
+        }
+        let line = line.trim_start();
+        if line.is_empty() {
+            Some(SkippedLine::Blank)
+        } else if line.starts_with(options.comment_char) {
+            Some(SkippedLine::Comment)
+        } else {
+            None
+        }
+    }
+}
+
+/// Numbers of input lines skipped with `--allow-comments`, updated while records are read
+#[derive(Debug, Default)]
+struct SkippedLines {
+    blank: Cell<u64>,
+    comment: Cell<u64>,
+}
+
+impl SkippedLines {
+    fn add(&self, line: SkippedLine) {
+        let count = match line {
+            SkippedLine::Blank => &self.blank,
+            SkippedLine::Comment => &self.comment,
+        };
+        count.set(count.get() + 1);
+    }
+
+    /// Copies the counts to `result` if lines are skipped
+    fn report(&self, result: &mut RunResult, options: &ProcessOptions) {
+        if options.allow_comments {
+            result.blank_lines = Some(self.blank.get());
+            result.comment_lines = Some(self.comment.get());
+        }
+    }
+}
+
+fn process_with<I, O>(
+    input: I,
+    output: RowWriter<'_, O>,
+    checkpointer: Option<Checkpointer<'_>>,
+    tally: Option<&mut Tally>,
+    options: &ProcessOptions,
+) -> anyhow::Result<Summary>
+where
+    I: BufRead,
+    O: Write,
+{
+    let mut result = RunResult::default();
+    let summary = process_counted(input, output, checkpointer, tally, &mut result, options);
+    let Some(p) = &options.result_file else {
+        return summary;
+    };
+    result.passed = result.read - result.rejected;
+    let written = serde_json::to_value(&result)
+        .map_err(anyhow::Error::from)
+        .and_then(|result| write_json_file(p, &result))
+        .with_context(|| format!("failed to write {}", p.display()));
+    let summary = summary?;
+    written?;
+    Ok(summary)
+}
+
+/// Filters the records of `input` and writes them to `output`, keeping `result` up to date as
+/// processing can fail at any point
+fn process_counted<I, O>(
+    input: I,
+    mut output: RowWriter<'_, O>,
+    mut checkpointer: Option<Checkpointer<'_>>,
+    mut tally: Option<&mut Tally>,
+    result: &mut RunResult,
+    options: &ProcessOptions,
+) -> anyhow::Result<Summary>
+where
+    I: BufRead,
+    O: Write,
+{
+    let raw_input_required = options.explode_prices
+        || !options.passthrough_fields.is_empty()
+        || !options.project.is_empty()
+        || !options.map.is_empty()
+        || options.format == OutputFormat::EnrichedJson;
+    if options.input_format == InputFormat::Csv && raw_input_required {
+        anyhow::bail!(
+            "--explode-prices, --passthrough-fields, --project, --map and --format enriched-json \
+            require --input-format jsonl"
+        );
+    }
+    if options.weight_field.is_some() && options.aggregation != Aggregation::Median {
+        anyhow::bail!("--weight-field requires --aggregation median");
+    }
+    if options.mode_precision.is_some() && options.aggregation != Aggregation::Mode {
+        anyhow::bail!("--mode-precision requires --aggregation mode");
+    }
+    let billing_codes = billing_codes(options)?;
+    let expected_codes = expected_codes(options)?;
+    let mut wasm_filter = options
+        .filter_wasm
+        .as_deref()
+        .map(WasmFilter::load)
+        .transpose()?;
+    let mut progress = options
+        .progress
+        .then(|| Progress::new(options.progress_format));
+    let mut stats = options.stats_file.as_ref().map(|_| Stats::default());
+    let expected_ranges = expected_ranges(options)?;
+    let mut histogram = options.group_size_histogram.then(BTreeMap::<u64, u64>::new);
+    let mut distinct_rates = options.measure_cardinality.then(HyperLogLog::new);
+    let mut outlier_candidates = options.outliers.map(|_| Vec::new());
+    let mut first_lines = options.error_on_duplicate_code.then(HashMap::new);
+    let mut violations = Vec::new();
+    let mut errors = 0;
+    // Records written that passed filters and thresholds, unlike those of `--include-rejected`
+    let mut matched = 0;
+    let mut error_categories = options
+        .collect_errors
+        .then(BTreeMap::<ErrorCategory, u64>::new);
+    let profiling = options.profile.then(Profiling::start);
+    let report_errors = |categories: &Option<BTreeMap<_, _>>| {
+        if let Some(categories) = categories {
+            eprint!("{}", error_report(categories));
+        }
+    };
+    let skipped = SkippedLines::default();
+    for (line, r) in numbered_records(input, Some(&skipped), options) {
+        skipped.report(result, options);
+        if let Some(checkpointer) = &mut checkpointer {
+            checkpointer.update(line, &mut output)?;
+        }
+        if let Some(progress) = &mut progress {
+            progress.update(&output.summary);
+        }
+        if let Some(tally) = &mut tally {
+            tally.update(&output.summary, errors);
+        }
+        let r = match (r, &mut first_lines) {
+            (Ok((record, rate)), Some(first_lines)) => {
+                let code = options.code_normalize.apply(&record.billing_code);
+                match first_lines.get(code.as_ref()) {
+                    Some(&first_line) => Err(DuplicateCode {
+                        billing_code: record.billing_code,
+                        first_line,
+                    }
+                    .into()),
+                    None => {
+                        first_lines.insert(code.into_owned(), line);
+                        Ok((record, rate))
+                    }
+                }
+            }
+            (r, _) => r,
+        };
+        if let (Err(e), Some(categories)) = (&r, &mut error_categories) {
+            *categories.entry(ErrorCategory::of(e)).or_default() += 1;
+        }
+        let (mut r, mut rate) = match (
+            r.with_context(|| format!("error on line {line}")),
+            options.max_errors,
+        ) {
+            (Ok(r), _) => r,
+            (Err(e), Some(max_errors)) if errors < max_errors => {
+                errors += 1;
+                result.errors = errors;
+                if !options.quiet {
+                    eprintln!("warning: skipping line: {e:#}");
+                }
+                continue;
+            }
+            (Err(e), Some(max_errors)) => {
+                result.errors = errors + 1;
+                report_errors(&error_categories);
+                return Err(e.context(format!(
+                    "{} lines failed, more than the maximum of {max_errors}",
+                    errors + 1
+                )));
+            }
+            (Err(e), None) => {
+                result.errors = errors + 1;
+                report_errors(&error_categories);
+                return Err(e);
+            }
+        };
+        output.summary.read += 1;
+        result.read += 1;
+        if let (Some(histogram), Some(group_sizes)) = (&mut histogram, rate.group_sizes.take()) {
+            for size in group_sizes {
+                *histogram.entry(size).or_default() += 1;
+            }
+        }
+        if let Some(distinct_rates) = &mut distinct_rates {
+            for &price in rate.prices.iter().flatten() {
+                distinct_rates.add(price);
+            }
+            if !options.explode_prices && !options.with_rates {
+                // Only collected for the estimate, so don't keep them in groups
+                rate.prices = None;
+            }
+        }
+        if options.with_rates {
+            r.rates = rate.prices.take().unwrap_or_default();
+        }
+        if options.rate_frequencies {
+            r.rate_frequencies = rate.frequencies();
+        }
+        r.name = options.normalize_name.apply(r.name);
+        let code = options.code_normalize.apply(&r.billing_code);
+        let code_matches = billing_codes
+            .as_ref()
+            .is_none_or(|codes| codes.contains(code.as_ref()))
+            && options.shard.is_none_or(|shard| shard.contains(&code));
+        if !code_matches
+            || !options.date_matches(r.date)
+            || !match &mut wasm_filter {
+                Some(filter) => filter
+                    .keep(&r)
+                    .with_context(|| format!("error on line {line}"))?,
+                None => true,
+            }
+        {
+            result.rejected += 1;
+            continue;
+        }
+        if let Some(range) = expected_ranges.get(code.as_ref())
+            && !r.avg_rate.is_some_and(|avg| range.contains(avg))
+        {
+            violations.push(format!(
+                "line {line}: {} (expected {} to {})",
+                r.describe_rate(),
+                range.min,
+                range.max
+            ));
+        }
+        if let Some(groups) = &mut output.groups {
+            groups
+                .entry(r.key(&options.key))
+                .or_insert_with(|| AccumulatedRate::new(options))
+                .merge(rate);
+            if options
+                .emit_every
+                .is_some_and(|n| output.summary.read.is_multiple_of(n))
+            {
+                timed(Stage::Write, || output.write_snapshot())?;
+            }
+            continue;
+        }
+        let written = output.summary.written;
+        let mut passed = false;
+        if options.explode_prices {
+            for &negotiated_rate in rate.prices.iter().flatten() {
+                let price = PriceRecord {
+                    name: &r.name,
+                    billing_code: &r.billing_code,
+                    negotiated_rate,
+                };
+                let disposition = options.disposition(Some(negotiated_rate), &rate);
+                passed |= disposition == Disposition::Kept;
+                timed(Stage::Write, || output.write(&r, price, disposition))?;
+            }
+        } else if let Some(candidates) = &mut outlier_candidates {
+            // Written once the averages of all the records are known
+            if options.disposition(r.avg_rate, &rate) == Disposition::Kept {
+                candidates.push(r);
+            } else {
+                result.rejected += 1;
+            }
+            continue;
+        } else {
+            let disposition = options.disposition(r.avg_rate, &rate);
+            passed = disposition == Disposition::Kept;
+            timed(Stage::Write, || output.write(&r, &r, disposition))?;
+        }
+        if !passed {
+            result.rejected += 1;
+        } else if output.summary.written > written {
+            matched += 1;
+        }
+        if let Some(stats) = &mut stats
+            && output.summary.written > written
+        {
+            stats.add(&r);
+        }
+        if output.truncated || options.expect.is_some_and(|k| matched >= k) {
+            break;
+        }
+    }
+    skipped.report(result, options);
+    let mut average_rates = None;
+    if let (Some(candidates), Some(z)) = (outlier_candidates, options.outliers) {
+        let averages = candidates
+            .iter()
+            .filter_map(|r| r.avg_rate)
+            .fold(AccumulatedRate::default(), |averages, negotiated_rate| {
+                averages + NegotiatedPrice { negotiated_rate }
+            });
+        let distribution = averages.average().zip(averages.std_dev());
+        for r in candidates {
+            let outlier = r
+                .avg_rate
+                .zip(distribution)
+                .is_some_and(|(avg, (mean, std_dev))| (avg - mean).abs() > z * std_dev);
+            if !outlier {
+                result.rejected += 1;
+                continue;
+            }
+            let written = output.summary.written;
+            timed(Stage::Write, || output.write(&r, &r, Disposition::Kept))?;
+            if let Some(stats) = &mut stats
+                && output.summary.written > written
+            {
+                stats.add(&r);
+            }
+            if output.truncated {
+                break;
+            }
+        }
+        average_rates = Some(distribution);
+    }
+    timed(Stage::Write, || output.output.flush())?;
+    let output_bytes = output.output.bytes_written();
+    let present = output.present.take();
+    let summary = timed(Stage::Write, || output.finish())?;
+    if let Some(tally) = &mut tally {
+        tally.update(&summary, errors);
+    }
+    if let Some(checkpointer) = &checkpointer {
+        checkpointer.checkpoint(output_bytes)?;
+    }
+    if let Some(progress) = &progress {
+        progress.report(&summary);
+    }
+    if let Some(histogram) = &histogram {
+        eprint!("{}", group_size_histogram(histogram));
+    }
+    if let (Some(expected), Some(present)) = (&expected_codes, &present) {
+        eprint!("{}", coverage_report(expected, present));
+    }
+    if let Some(distinct_rates) = &distinct_rates {
+        eprintln!("distinct rates: ~{:.0}", distinct_rates.estimate());
+    }
+    match average_rates.filter(|_| !options.quiet) {
+        Some(Some((mean, std_dev))) => {
+            eprintln!("average rates: mean {mean}, standard deviation {std_dev}");
+        }
+        Some(None) => eprintln!("average rates: too few to find outliers"),
+        None => {}
+    }
+    report_errors(&error_categories);
+    if let Some(profiling) = &profiling {
+        eprint!("{}", profile_report(&profiling.stages()));
+    }
+    if let (Some(stats), Some(p)) = (&stats, &options.stats_file) {
+        write_json_file(p, &stats.to_json(&summary))
+            .with_context(|| format!("failed to write {}", p.display()))?;
+    }
+    if options.error_on_empty_input && summary.read == 0 {
+        anyhow::bail!("no records were read from the input");
+    }
+    if !violations.is_empty() {
+        anyhow::bail!(
+            "{} records with an average rate outside the expected range:\n{}",
+            violations.len(),
+            violations.join("\n")
+        );
+    }
+    Ok(summary)
+}
+
+fn write_json_file(p: &Path, value: &Value) -> anyhow::Result<()> {
+    let mut file = BufWriter::new(File::create(p)?);
+    serde_json::to_writer_pretty(&mut file, value)?;
+    writeln!(file)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Statistics about the records written, collected with `--stats-file`
+#[derive(Default)]
+struct Stats {
+    billing_codes: HashSet<String>,
+    /// Distribution of the average rates of written records
+    avg_rates: AccumulatedRate,
+}
+
+impl Stats {
+    fn add(&mut self, record: &Record) {
+        if !self.billing_codes.contains(&record.billing_code) {
+            self.billing_codes.insert(record.billing_code.clone());
+        }
+        if let Some(negotiated_rate) = record.avg_rate {
+            let avg_rates = std::mem::take(&mut self.avg_rates);
+            self.avg_rates = avg_rates + NegotiatedPrice { negotiated_rate };
+        }
+    }
+
+    fn to_json(&self, summary: &Summary) -> Value {
+        let avg_rates = &self.avg_rates;
+        serde_json::json!({
+            "read": summary.read,
+            "written": summary.written,
+            "distinct_billing_codes": self.billing_codes.len(),
+            "avg_rate": {
+                "count": avg_rates.count(),
+                "min": avg_rates.min(),
+                "max": avg_rates.max(),
+                "mean": avg_rates.average(),
+                "std_dev": avg_rates.std_dev(),
+            },
+        })
+    }
+}
+
+/// Formats the number of groups of negotiated prices of each size, one size per line
+fn group_size_histogram(histogram: &BTreeMap<u64, u64>) -> String {
+    let mut text = String::from("prices per group: groups\n");
+    for (size, groups) in histogram {
+        text += &format!("{size}: {groups}\n");
+    }
+    text
+}
+
+/// Estimator of the number of distinct rates, using HyperLogLog
+///
+/// Each rate is hashed to a register picked by the first `HLL_PRECISION` bits of its hash, which
+/// keeps the longest run of leading zeros seen in the remaining bits. With 2^14 registers, the
+/// standard error of the estimate is 1.04 / sqrt(2^14), about 0.8%.
+struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+/// Number of hash bits selecting a register
+const HLL_PRECISION: u32 = 14;
+
+impl HyperLogLog {
+    fn new() -> Self {
+        Self {
+            registers: vec![0; 1 << HLL_PRECISION],
+        }
+    }
+
+    fn add(&mut self, rate: f64) {
+        let mut hasher = std::hash::DefaultHasher::new();
+        // Adding zero turns -0.0 into 0.0 so both are counted as the same rate
+        (rate + 0.0).to_bits().hash(&mut hasher);
+        let hash = hasher.finish();
+        let register = (hash >> (64 - HLL_PRECISION)) as usize;
+        let rest = hash << HLL_PRECISION;
+        let rank = (rest.leading_zeros() + 1).min(64 - HLL_PRECISION + 1) as u8;
+        self.registers[register] = self.registers[register].max(rank);
+    }
+
+    /// Returns the estimated number of distinct rates added
+    fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum = self
+            .registers
+            .iter()
+            .map(|&rank| 2f64.powi(-i32::from(rank)))
+            .sum::<f64>();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&rank| rank == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            // Linear counting is more accurate for small cardinalities
+            m * (m / zeros as f64).ln()
+        } else {
+            estimate
+        }
+    }
+}
+
+/// Category of errors affecting an input line
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum ErrorCategory {
+    Read,
+    Syntax,
+    NumberOutOfRange,
+    MissingField,
+    DuplicateField,
+    TypeMismatch,
+    InvalidValue,
+    Csv,
+    DuplicateCode,
+}
+
+impl ErrorCategory {
+    /// Returns the category of `e`, an error reading or parsing a line
+    fn of(e: &anyhow::Error) -> Self {
+        for cause in e.chain() {
+            if let Some(e) = cause.downcast_ref::<serde_json::Error>() {
+                // serde_json only exposes the details of data errors through their messages
+                let message = e.to_string();
+                return match e.classify() {
+                    serde_json::error::Category::Io => Self::Read,
+                    _ if message.starts_with("number out of range") => Self::NumberOutOfRange,
+                    serde_json::error::Category::Syntax | serde_json::error::Category::Eof => {
+                        Self::Syntax
+                    }
+                    serde_json::error::Category::Data => match message {
+                        _ if message.starts_with("missing field") => Self::MissingField,
+                        _ if message.starts_with("duplicate field") => Self::DuplicateField,
+                        _ if message.starts_with("invalid type") => Self::TypeMismatch,
+                        _ => Self::InvalidValue,
+                    },
+                };
+            }
+            if cause.is::<csv::Error>() {
+                return Self::Csv;
+            }
+            if cause.is::<DuplicateCode>() {
+                return Self::DuplicateCode;
+            }
+        }
+        Self::Read
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Syntax => "syntax",
+            Self::NumberOutOfRange => "number out of range",
+            Self::MissingField => "missing field",
+            Self::DuplicateField => "duplicate field",
+            Self::TypeMismatch => "type mismatch",
+            Self::InvalidValue => "invalid value",
+            Self::Csv => "csv",
+            Self::DuplicateCode => "duplicate billing code",
+        }
+    }
+}
+
+/// Error of a record with the billing code of an earlier record, with `--error-on-duplicate-code`
+#[derive(Debug)]
+struct DuplicateCode {
+    billing_code: String,
+    /// Line of the first record with the billing code
+    first_line: usize,
+}
+
+impl fmt::Display for DuplicateCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "duplicate billing code {}, first seen on line {}",
+            self.billing_code, self.first_line
+        )
+    }
+}
+
+impl std::error::Error for DuplicateCode {}
+
+/// Formats the number of failed lines of each error category, one category per line
+fn error_report(categories: &BTreeMap<ErrorCategory, u64>) -> String {
+    let mut text = String::from("errors by category: lines\n");
+    for (category, lines) in categories {
+        text += &format!("{}: {lines}\n", category.name());
+    }
+    text
+}
+
+/// Stage of processing timed with `--profile`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    Read,
+    Decompress,
+    Parse,
+    Aggregate,
+    Write,
+}
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Decompress => "decompress",
+            Self::Parse => "parse",
+            Self::Aggregate => "aggregate",
+            Self::Write => "write",
+        }
+    }
+}
+
+/// Wall time spent in each stage, excluding the time spent in stages nested in it
+#[derive(Debug, Default)]
+struct Profile {
+    stages: BTreeMap<Stage, Duration>,
+    /// Total time of the timed sections that have completed, used to subtract nested sections
+    /// from their enclosing one
+    completed: Duration,
+}
+
+thread_local! {
+    static PROFILE: RefCell<Option<Profile>> = const { RefCell::new(None) };
+}
+
+/// Guard timing the stages run on the current thread until it is dropped
+struct Profiling;
+
+impl Profiling {
+    fn start() -> Self {
+        PROFILE.set(Some(Profile::default()));
+        Self
+    }
+
+    fn stages(&self) -> BTreeMap<Stage, Duration> {
+        PROFILE.with_borrow(|p| p.as_ref().map(|p| p.stages.clone()).unwrap_or_default())
+    }
+}
+
+impl Drop for Profiling {
+    fn drop(&mut self) {
+        PROFILE.set(None);
+    }
+}
+
+/// Runs `f`, adding its wall time to `stage` if profiling
+///
+/// Sections can be nested, e.g. reading compressed input both decompresses and reads, in which
+/// case the inner section's time is only counted in its own stage.
+fn timed<T>(stage: Stage, f: impl FnOnce() -> T) -> T {
+    let Some(completed) = PROFILE.with_borrow(|p| p.as_ref().map(|p| p.completed)) else {
+        return f();
+    };
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+    PROFILE.with_borrow_mut(|p| {
+        if let Some(p) = p {
+            let nested = p.completed.saturating_sub(completed);
+            *p.stages.entry(stage).or_default() += elapsed.saturating_sub(nested);
+            p.completed = completed + elapsed;
+        }
+    });
+    result
+}
+
+/// Returns the time spent in each stage and its share of the total
+fn profile_report(stages: &BTreeMap<Stage, Duration>) -> String {
+    let total = stages.values().sum::<Duration>().as_secs_f64();
+    let mut text = String::from("time by stage:\n");
+    for (stage, time) in stages {
+        let seconds = time.as_secs_f64();
+        let share = if total > 0.0 { seconds / total } else { 0.0 };
+        text += &format!("{}: {seconds:.3}s ({:.1}%)\n", stage.name(), share * 100.0);
+    }
+    text
+}
+
+/// Reader wrapper timing reads from the inner reader as `stage` with `--profile`
+pub struct Profiled<R> {
+    inner: R,
+    stage: Stage,
+}
+
+impl<R> Profiled<R> {
+    pub fn new(inner: R, stage: Stage) -> Self {
+        Self { inner, stage }
+    }
+}
+
+impl<R: Read> Read for Profiled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        timed(self.stage, || self.inner.read(buf))
+    }
+}
+
+impl<R: BufRead> BufRead for Profiled<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        timed(self.stage, || self.inner.fill_buf())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt);
+    }
+}
+
+/// Throttled reporter of processing progress on stderr
+struct Progress {
+    format: ProgressFormat,
+    start: Instant,
+    last: Instant,
+}
+
+impl Progress {
+    fn new(format: ProgressFormat) -> Self {
+        let now = Instant::now();
+        Self {
+            format,
+            start: now,
+            last: now,
+        }
+    }
+
+    /// Reports `summary` if enough time elapsed since the last report
+    fn update(&mut self, summary: &Summary) {
+        let now = Instant::now();
+        if now.duration_since(self.last) >= PROGRESS_INTERVAL {
+            self.last = now;
+            self.report(summary);
+        }
+    }
+
+    fn report(&self, summary: &Summary) {
+        eprintln!(
+            "{}",
+            progress_line(self.format, summary, self.start.elapsed())
+        );
+    }
+}
+
+fn progress_line(format: ProgressFormat, summary: &Summary, elapsed: Duration) -> String {
+    match format {
+        ProgressFormat::Text => format!(
+            "read {} records, wrote {} in {:.1}s",
+            summary.read,
+            summary.written,
+            elapsed.as_secs_f64()
+        ),
+        ProgressFormat::Json => serde_json::json!({
+            "read": summary.read,
+            "written": summary.written,
+            "elapsed_ms": elapsed.as_millis() as u64,
+        })
+        .to_string(),
+    }
+}
+
+/// Output stage writing rows, or tallying them per key with `--count-only` or `--emit-every`
+struct RowWriter<'a, W: Write> {
+    output: Sink<W>,
+    options: &'a ProcessOptions,
+    split: Option<Split<'a, W>>,
+    /// Records written to the current part
+    part_records: u64,
+    counts: Option<BTreeMap<Vec<String>, u64>>,
+    groups: Option<BTreeMap<Vec<String>, AccumulatedRate>>,
+    /// Snapshots of `groups` written so far, and the number of records read at the last one
+    snapshots: u64,
+    snapshot_read: u64,
+    summary: Summary,
+    /// Random number generator deciding which rows are written with `--sample-rate`
+    sampler: Option<StdRng>,
+    /// Average rate of each billing code in the `--baseline` output
+    baseline: Option<HashMap<String, Option<f64>>>,
+    /// Average rates of the records written, for `--append-summary-row`
+    total: Option<AccumulatedRate>,
+    /// Whether a row was dropped to stay within `--max-output-bytes`, after which nothing else is
+    /// written
+    truncated: bool,
+    /// Average rates of the last kept records, for `--rolling-window`
+    window: Option<VecDeque<f64>>,
+    /// Where the CSV rows written are recorded when finishing, for [`process_verified`]
+    recorded: Option<&'a mut Vec<Vec<Value>>>,
+    /// Normalized billing codes of the rows written, for `--expected-codes`
+    present: Option<HashSet<String>>,
+}
+
+/// Fields of written rows holding a rate, scaled with `--scale`
+///
+/// `prev_rate` and `delta` are computed from the scaled rates instead, as the baseline holds them.
+const RATE_FIELDS: [&str; 3] = ["avg_rate", "negotiated_rate", "rolling_avg_rate"];
+
+/// State to split output across several writers
+struct Split<'a, W> {
+    max_bytes: u64,
+    open: Box<dyn FnMut(u64) -> anyhow::Result<W> + 'a>,
+    part: u64,
+}
+
+impl<'a, W: Write> RowWriter<'a, W> {
+    fn new(
+        output: W,
+        split: Option<Split<'a, W>>,
+        resume: Resume,
+        options: &'a ProcessOptions,
+    ) -> anyhow::Result<Self> {
+        if let Some(rate) = options.sample_rate
+            && !(0.0..=1.0).contains(&rate)
+        {
+            anyhow::bail!("--sample-rate must be between 0 and 1, got {rate}");
+        }
+        options.check_columns()?;
+        if options.append_summary_row && options.format != OutputFormat::Csv {
+            anyhow::bail!("--append-summary-row is only supported with --format csv");
+        }
+        let aggregated =
+            options.explode_prices || options.count_only || options.emit_every.is_some();
+        let selected = !options.columns.is_empty() || !options.project.is_empty();
+        if options.format == OutputFormat::GroupedJson && aggregated && !options.explode_prices {
+            anyhow::bail!(
+                "--format grouped-json is not supported with --count-only and --emit-every"
+            );
+        }
+        if options.format == OutputFormat::EnrichedJson && (aggregated || selected) {
+            anyhow::bail!(
+                "--format enriched-json is not supported with --explode-prices, --count-only, \
+                --emit-every, --columns and --project"
+            );
+        }
+        let sampler = options.sample_rate.map(|_| match options.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        });
+        Ok(Self {
+            output: Sink::new(output, resume, options)?,
+            options,
+            split,
+            part_records: 0,
+            counts: options.count_only.then(BTreeMap::new),
+            groups: options.emit_every.map(|_| BTreeMap::new()),
+            snapshots: 0,
+            snapshot_read: 0,
+            summary: Summary::default(),
+            sampler,
+            baseline: baseline(options)?,
+            total: options.append_summary_row.then(AccumulatedRate::default),
+            truncated: false,
+            window: options
+                .rolling_window
+                .map(|n| VecDeque::with_capacity(n.try_into().unwrap_or(0))),
+            recorded: None,
+            present: options.expected_codes.as_ref().map(|_| HashSet::new()),
+        })
+    }
+
+    /// Writes `row` derived from `record` if its disposition allows it, annotated with that
+    /// disposition if requested
+    fn write<R>(&mut self, record: &Record, row: R, disposition: Disposition) -> anyhow::Result<()>
+    where
+        R: Serialize,
+    {
+        if disposition != Disposition::Kept && !self.options.include_rejected {
+            return Ok(());
+        }
+        if let (Some(window), Some(rate)) = (&mut self.window, record.avg_rate)
+            && disposition == Disposition::Kept
+        {
+            if window.len() as u64 == self.options.rolling_window.unwrap_or(u64::MAX) {
+                window.pop_front();
+            }
+            window.push_back(rate);
+        }
+        if let (Some(sampler), Some(rate)) = (&mut self.sampler, self.options.sample_rate)
+            && !sampler.random_bool(rate)
+        {
+            return Ok(());
+        }
+        let change = match &self.baseline {
+            Some(baseline) => match rate_change(baseline, record, self.options) {
+                Some(change) => Some(change),
+                None => return Ok(()),
+            },
+            None => None,
+        };
+        if self.counts.is_some() {
+            let key = record.key(&self.options.key);
+            if self.options.assume_sorted {
+                self.end_sorted_group(&key)?;
+            }
+            if let Some(counts) = &mut self.counts {
+                *counts.entry(key).or_default() += 1;
+            }
+            self.summary.written += 1;
+            return Ok(());
+        }
+        if self.truncated {
+            return Ok(());
+        }
+        self.split_if_full()?;
+        let passthrough = self
+            .options
+            .passthrough_fields
+            .iter()
+            .zip(&record.passthrough);
+        let status = self
+            .options
+            .annotate_status
+            .then(|| serde_json::to_value(disposition))
+            .transpose()?;
+        let change = change.into_iter().flat_map(|(prev_rate, delta)| {
+            [("prev_rate".into(), prev_rate), ("delta".into(), delta)]
+        });
+        let rates = self.options.with_rates.then(|| {
+            let rates = record
+                .rates
+                .iter()
+                .map(|&rate| self.options.packed_rate(rate))
+                .collect::<Vec<_>>();
+            ("rates".into(), rates.join(";").into())
+        });
+        let frequencies = self.options.rate_frequencies.then(|| {
+            let frequencies = record
+                .rate_frequencies
+                .iter()
+                .map(|&(rate, count)| format!("{}:{count}", self.options.packed_rate(rate)))
+                .collect::<Vec<_>>();
+            ("rate_frequencies".into(), frequencies.join(";").into())
+        });
+        let rolling = self.window.as_ref().map(|window| {
+            let mean =
+                (!window.is_empty()).then(|| window.iter().sum::<f64>() / window.len() as f64);
+            ("rolling_avg_rate".into(), mean.into())
+        });
+        let extra = passthrough
+            .map(|(field, value)| (field.clone(), value.clone()))
+            .chain(change)
+            .chain(rates)
+            .chain(frequencies)
+            .chain(rolling)
+            .chain(status.map(|status| ("status".into(), status)))
+            .collect();
+        let options = self.options;
+        if let Some(source) = &record.source {
+            let omit_null = options.json_null_mode == JsonNullMode::Omit;
+            let mut row = source.clone();
+            let added = [("avg_rate".into(), options.rate_value(record.avg_rate))];
+            row.extend(
+                added
+                    .into_iter()
+                    .chain(extra)
+                    .filter(|(_, value)| !(omit_null && value.is_null())),
+            );
+            self.output.write_object(row)?;
+        } else if options.scale.is_none()
+            && options.project.is_empty()
+            && options.columns.is_empty()
+        {
+            self.output.write_row(row, extra)?;
+        } else {
+            let mut row = row_object(row, extra)?;
+            if self.options.scale.is_some() {
+                for field in RATE_FIELDS {
+                    if let Some(rate) = row.get_mut(field) {
+                        *rate = self.options.rate_value(rate.as_f64());
+                    }
+                }
+            }
+            if !self.options.project.is_empty() {
+                let projected = self.options.project.iter().map(|p| p.name.clone());
+                let rest = row
+                    .into_iter()
+                    .filter(|(field, _)| field != "name" && field != "billing_code");
+                row = projected
+                    .zip(record.projected.iter().cloned())
+                    .chain(rest)
+                    .collect();
+            }
+            self.output.write_object(options.select_columns(row))?;
+        }
+        if self.record_written()? {
+            self.summary.written += 1;
+            if let (Some(total), Some(negotiated_rate)) = (&mut self.total, record.avg_rate) {
+                *total = std::mem::take(total) + NegotiatedPrice { negotiated_rate };
+            }
+            if let Some(present) = &mut self.present {
+                let code = self.options.code_normalize.apply(&record.billing_code);
+                present.insert(code.into_owned());
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the `TOTAL` row of `--append-summary-row`
+    fn write_total(&mut self) -> anyhow::Result<()> {
+        let Some(total) = self.total.take() else {
+            return Ok(());
+        };
+        let header = self.options.computed_columns();
+        let fields = header
+            .iter()
+            .map(|column| match column.as_str() {
+                "name" => "TOTAL".into(),
+                "billing_code" => self.summary.written.into(),
+                "avg_rate" => self.options.rate_value(total.average()),
+                _ => Value::Null,
+            })
+            .collect();
+        self.write_fields(&header, fields)?;
+        Ok(())
+    }
+
+    /// Writes a row of `fields` named by `header`, the computed columns, writing the header of
+    /// the columns selected by `--columns` first in each part
+    ///
+    /// Returns whether the row was written, rows not being written once the output is truncated.
+    fn write_fields(&mut self, header: &[String], fields: Vec<Value>) -> anyhow::Result<bool> {
+        if self.truncated {
+            return Ok(false);
+        }
+        self.split_if_full()?;
+        let options = self.options;
+        let (header, fields) = if options.columns.is_empty() {
+            (Cow::Borrowed(header), fields)
+        } else {
+            let row = options.select_columns(header.iter().cloned().zip(fields).collect());
+            (
+                Cow::Owned(options.columns.clone()),
+                row.into_values().collect(),
+            )
+        };
+        if self.part_records == 0 {
+            self.output.write_header(&header)?;
+        }
+        self.output.write_fields(&header, fields)?;
+        self.record_written()
+    }
+
+    /// Writes the count of the group being counted with `--assume-sorted` if `key` starts the
+    /// next one
+    fn end_sorted_group(&mut self, key: &[String]) -> anyhow::Result<()> {
+        let Some(counts) = &mut self.counts else {
+            return Ok(());
+        };
+        let Some((last, _)) = counts.last_key_value() else {
+            return Ok(());
+        };
+        match key.cmp(last) {
+            Ordering::Equal => Ok(()),
+            Ordering::Greater => {
+                let counts = std::mem::take(counts);
+                self.write_counts(counts)
+            }
+            Ordering::Less => anyhow::bail!(
+                "input is not sorted by key: {} follows {}",
+                key.join(","),
+                last.join(",")
+            ),
+        }
+    }
+
+    /// Writes a row of each key and its count
+    fn write_counts(&mut self, counts: BTreeMap<Vec<String>, u64>) -> anyhow::Result<()> {
+        let header = self.options.computed_columns();
+        for (key, count) in counts {
+            let fields = key.into_iter().map(Into::into).chain([count.into()]);
+            self.write_fields(&header, fields.collect())?;
+        }
+        Ok(())
+    }
+
+    /// Starts writing to a new part if the current one is full
+    fn split_if_full(&mut self) -> anyhow::Result<()> {
+        let Some(split) = &mut self.split else {
+            return Ok(());
+        };
+        if self.part_records == 0 || self.output.bytes_written() < split.max_bytes {
+            return Ok(());
+        }
+        split.part += 1;
+        let next = Sink::new((split.open)(split.part)?, Resume::Start, self.options)?;
+        std::mem::replace(&mut self.output, next).finish()?;
+        self.part_records = 0;
+        Ok(())
+    }
+
+    /// Accounts for the row just written, returning whether it was kept within
+    /// `--max-output-bytes`
+    fn record_written(&mut self) -> anyhow::Result<bool> {
+        if self.options.max_output_bytes.is_some() {
+            // Flush to write or drop the row as a whole
+            self.output.flush()?;
+            if self.output.limit_reached() {
+                self.truncated = true;
+                return Ok(false);
+            }
+        }
+        self.part_records += 1;
+        if self.split.is_some() {
+            // Flush so that the byte count of the part is up to date
+            self.output.flush()?;
+        }
+        Ok(true)
+    }
+
+    /// Writes the average rate of each group accumulated so far
+    fn write_snapshot(&mut self) -> anyhow::Result<()> {
+        let Some(groups) = self.groups.take() else {
+            return Ok(());
+        };
+        self.snapshots += 1;
+        self.snapshot_read = self.summary.read;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let header = self.options.computed_columns();
+        let mut result = Ok(());
+        for (key, rate) in &groups {
+            let avg_rate = rate.aggregate();
+            let disposition = self.options.disposition(avg_rate, rate);
+            if disposition != Disposition::Kept && !self.options.include_rejected {
+                continue;
+            }
+            let fields = [self.snapshots.into(), timestamp.into()]
+                .into_iter()
+                .chain(key.iter().map(|k| k.as_str().into()))
+                .chain([self.options.rate_value(avg_rate), rate.count.into()])
+                .collect();
+            match self.write_fields(&header, fields) {
+                Ok(written) => self.summary.written += u64::from(written),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.groups = Some(groups);
+        result
+    }
+
+    /// Records the rows written as CSV, including the header, in `rows` when finishing
+    fn recording(mut self, rows: &'a mut Vec<Vec<Value>>) -> anyhow::Result<Self> {
+        let Sink::Csv(output) = &mut self.output else {
+            anyhow::bail!("round-trip verification is only supported with --format csv");
+        };
+        output.written = Some(Vec::new());
+        self.recorded = Some(rows);
+        Ok(self)
+    }
+
+    fn finish(mut self) -> anyhow::Result<Summary> {
+        if self.summary.read > self.snapshot_read {
+            self.write_snapshot()?;
+        }
+        if let Some(counts) = self.counts.take() {
+            self.write_counts(counts)?;
+        }
+        self.write_total()?;
+        if let (Some(recorded), Sink::Csv(output)) = (&mut self.recorded, &mut self.output) {
+            **recorded = output.written.take().unwrap_or_default();
+        }
+        self.output.finish()?;
+        if self.truncated && !self.options.quiet {
+            eprintln!("warning: output truncated to stay within --max-output-bytes");
+        }
+        Ok(self.summary)
+    }
+}
+
+/// Returns a builder of CSV writers configured by `options`
+fn csv_writer_builder(options: &ProcessOptions) -> anyhow::Result<csv::WriterBuilder> {
+    let delimiter = output_delimiter(options)?;
+    if options.decimal_comma && delimiter == b',' {
+        anyhow::bail!("--decimal-comma requires a --delimiter-out other than ','");
+    }
+    let mut builder = csv::WriterBuilder::new();
+    builder
+        .delimiter(delimiter)
+        .quote_style(options.csv_quote.into());
+    Ok(builder)
+}
+
+/// Returns the field delimiter of CSV output
+fn output_delimiter(options: &ProcessOptions) -> anyhow::Result<u8> {
+    u8::try_from(options.delimiter_out)
+        .ok()
+        .filter(u8::is_ascii)
+        .context("output delimiter must be an ASCII character")
+}
+
+/// Writer of rows in the output format
+enum Sink<W: Write> {
+    Csv(Box<CsvWriter<W>>),
+    Json(JsonWriter<W>),
+    Arrow(Box<ArrowWriter<W>>),
+    Table(TableWriter<W>),
+}
+
+/// How much of the output a previous run wrote before the output is continued
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Resume {
+    /// Nothing, the output being new
+    Start,
+    /// The preamble of comments, but not the CSV header written with the first row
+    AfterPreamble,
+    /// The preamble and the CSV header, if any
+    AfterHeader,
+}
+
+/// Returns the comment lines written at the start of CSV output
+fn preamble(options: &ProcessOptions) -> impl Iterator<Item = String> {
+    let comments = options.output_comment.iter().flat_map(|c| c.lines());
+    comments.map(|comment| format!("{}{comment}\n", options.comment_char))
+}
+
+/// Returns the length of the [`preamble`] of CSV output
+fn preamble_len(options: &ProcessOptions) -> u64 {
+    if options.format != OutputFormat::Csv {
+        return 0;
+    }
+    preamble(options).map(|line| line.len() as u64).sum()
+}
+
+impl<W: Write> Sink<W> {
+    /// Writes the preamble of the output format to `output` and returns a sink to write rows after
+    /// it
+    ///
+    /// What a previous run already wrote according to `resume`, as `output` then continues it, is
+    /// not written again.
+    fn new(output: W, resume: Resume, options: &ProcessOptions) -> anyhow::Result<Self> {
+        let resumed = resume != Resume::Start;
+        let mut output = CountingWriter {
+            inner: output,
+            count: 0,
+            limit: None,
+        };
+        let limit = |max_bytes| Limit {
+            max_bytes,
+            staged: Vec::new(),
+            reached: false,
+        };
+        let grouped = options.format == OutputFormat::GroupedJson;
+        if options.pretty && options.format != OutputFormat::Json && !grouped {
+            anyhow::bail!("--pretty is only supported with --format json or grouped-json");
+        }
+        let json = matches!(
+            options.format,
+            OutputFormat::Json
+                | OutputFormat::Ndjson
+                | OutputFormat::EnrichedJson
+                | OutputFormat::GroupedJson
+        );
+        if options.json_null_mode != JsonNullMode::Null && !json {
+            anyhow::bail!("--json-null-mode is only supported with JSON formats");
+        }
+        if options.format == OutputFormat::Csv {
+            for line in preamble(options).filter(|_| !resumed) {
+                output
+                    .write_all(line.as_bytes())
+                    .context("failed to write comment")?;
+            }
+            output.limit = options.max_output_bytes.map(limit);
+            let has_header = resume == Resume::AfterHeader;
+            return Ok(Sink::Csv(Box::new(CsvWriter {
+                output: csv_writer_builder(options)?
+                    .has_headers(!has_header)
+                    .from_writer(output),
+                decimal_comma: options.decimal_comma,
+                has_header,
+                written: None,
+            })));
+        }
+        if !options.output_comment.is_empty() {
+            anyhow::bail!("--output-comment is only supported with --format csv");
+        }
+        if options.decimal_comma {
+            anyhow::bail!("--decimal-comma is only supported with --format csv");
+        }
+        if options.format == OutputFormat::Arrow {
+            return Ok(Sink::Arrow(Box::new(ArrowWriter::new(
+                output, resumed, options,
+            )?)));
+        }
+        if options.format == OutputFormat::Table {
+            if resumed || options.max_output_bytes.is_some() {
+                anyhow::bail!(
+                    "resuming and --max-output-bytes are not supported with --format table"
+                );
+            }
+            return Ok(Sink::Table(TableWriter {
+                output,
+                header: None,
+                rows: Vec::new(),
+                max_rows: options.table_max_rows,
+                dropped: 0,
+                quiet: options.quiet,
+            }));
+        }
+        let array = options.format == OutputFormat::Json;
+        if array && resumed {
+            anyhow::bail!("resuming is not supported with --format json");
+        }
+        if grouped && (resumed || options.max_output_bytes.is_some()) {
+            anyhow::bail!(
+                "resuming and --max-output-bytes are not supported with --format grouped-json"
+            );
+        }
+        if array {
+            output.write_all(b"[").context("failed to write output")?;
+        }
+        let reserved = if array {
+            JSON_ARRAY_END.len() as u64
+        } else {
+            0
+        };
+        output.limit = options
+            .max_output_bytes
+            .map(|max_bytes| limit(max_bytes.saturating_sub(reserved)));
+        // Null fields of input objects are kept, only added null fields being omitted
+        let omit_null = options.json_null_mode == JsonNullMode::Omit
+            && options.format != OutputFormat::EnrichedJson;
+        Ok(Sink::Json(JsonWriter {
+            output,
+            array,
+            pretty: options.pretty,
+            omit_null,
+            groups: grouped.then(serde_json::Map::new),
+            rows: 0,
+        }))
+    }
+
+    /// Returns whether a row was dropped to stay within `--max-output-bytes`
+    fn limit_reached(&self) -> bool {
+        let output = match self {
+            Sink::Csv(output) => output.output.get_ref(),
+            Sink::Json(output) => &output.output,
+            Sink::Arrow(output) => output.output.get_ref(),
+            Sink::Table(output) => &output.output,
+        };
+        output.limit.as_ref().is_some_and(|limit| limit.reached)
+    }
+
+    /// Returns the number of bytes written so far, excluding buffered bytes
+    fn bytes_written(&self) -> u64 {
+        match self {
+            Sink::Csv(output) => output.output.get_ref().count,
+            Sink::Json(output) => output.output.count,
+            Sink::Arrow(output) => output.output.get_ref().count,
+            Sink::Table(output) => output.output.count,
+        }
+    }
+
+    /// Writes the header of the following rows if the format has one
+    fn write_header(&mut self, header: &[String]) -> anyhow::Result<()> {
+        match self {
+            Sink::Csv(output) => output.write_header(header)?,
+            Sink::Table(output) => output.header = Some(header.to_vec()),
+            Sink::Json(_) | Sink::Arrow(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Writes `row`, followed by the `extra` named fields
+    fn write_row<R>(&mut self, row: R, extra: Vec<(String, Value)>) -> anyhow::Result<()>
+    where
+        R: Serialize,
+    {
+        match self {
+            Sink::Csv(output) => output.write_row(row, extra),
+            Sink::Json(output) => output.write(row_object(row, extra)?),
+            Sink::Arrow(output) => output.write(row_object(row, extra)?),
+            Sink::Table(output) => output.write_object(row_object(row, extra)?),
+        }
+    }
+
+    /// Writes a row of the fields of `row`
+    fn write_object(&mut self, row: serde_json::Map<String, Value>) -> anyhow::Result<()> {
+        match self {
+            Sink::Csv(output) => output.write_object(row),
+            Sink::Json(output) => output.write(row),
+            Sink::Arrow(output) => output.write(row),
+            Sink::Table(output) => output.write_object(row),
+        }
+    }
+
+    /// Writes a row of `fields` named by `header`
+    fn write_fields(&mut self, header: &[String], fields: Vec<Value>) -> anyhow::Result<()> {
+        match self {
+            Sink::Csv(output) => output.write_fields(fields),
+            Sink::Json(output) => {
+                let row = header.iter().cloned().zip(fields).collect();
+                output.write(row)
+            }
+            Sink::Arrow(output) => output.write(header.iter().cloned().zip(fields).collect()),
+            Sink::Table(output) => {
+                if output.header.is_none() {
+                    output.header = Some(header.to_vec());
+                }
+                output.write_fields(fields);
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        match self {
+            Sink::Csv(output) => output.output.flush(),
+            Sink::Json(output) => output.output.flush(),
+            Sink::Arrow(output) => return output.flush(),
+            Sink::Table(output) => output.output.flush(),
+        }
+        .context("failed to write output")
+    }
+
+    /// Writes the end of the output format and flushes
+    fn finish(mut self) -> anyhow::Result<()> {
+        match &mut self {
+            Sink::Csv(_) => {}
+            Sink::Json(output) => output.finish()?,
+            Sink::Arrow(output) => output.finish()?,
+            Sink::Table(output) => output.finish()?,
+        }
+        self.flush()
+    }
+}
+
+/// Returns the fields of `row` as a JSON object, followed by the `extra` named fields
+fn row_object<R>(
+    row: R,
+    extra: Vec<(String, Value)>,
+) -> anyhow::Result<serde_json::Map<String, Value>>
+where
+    R: Serialize,
+{
+    let Value::Object(mut row) = serde_json::to_value(row).context("failed to write record")?
+    else {
+        anyhow::bail!("failed to write record: row is not a struct");
+    };
+    row.extend(extra);
+    Ok(row)
+}
+
+/// Writer of rows as CSV
+struct CsvWriter<W: Write> {
+    output: csv::Writer<CountingWriter<W>>,
+    /// Whether floating-point numbers are written with a comma as decimal separator
+    decimal_comma: bool,
+    /// Whether a header was written explicitly
+    has_header: bool,
+    /// Fields of the header and rows written, if recorded
+    written: Option<Vec<Vec<Value>>>,
+}
+
+impl<W: Write> CsvWriter<W> {
+    fn write_header(&mut self, header: &[String]) -> anyhow::Result<()> {
+        self.output
+            .write_record(header)
+            .context("failed to write header")?;
+        self.has_header = true;
+        if let Some(written) = &mut self.written {
+            written.push(header.iter().map(|h| h.as_str().into()).collect());
+        }
+        Ok(())
+    }
+
+    fn write_row<R>(&mut self, row: R, extra: Vec<(String, Value)>) -> anyhow::Result<()>
+    where
+        R: Serialize,
+    {
+        if !self.decimal_comma && extra.is_empty() && self.written.is_none() {
+            return self.output.serialize(row).context("failed to write record");
+        }
+        // The CSV serializer cannot add fields to a struct nor change how it formats numbers, so
+        // go through JSON values instead
+        self.write_object(row_object(row, extra)?)
+    }
+
+    fn write_object(&mut self, row: serde_json::Map<String, Value>) -> anyhow::Result<()> {
+        if !self.has_header {
+            self.write_header(&row.keys().cloned().collect::<Vec<_>>())?;
+        }
+        self.write_fields(row.into_iter().map(|(_, field)| field).collect())
+    }
+
+    fn write_fields(&mut self, fields: Vec<Value>) -> anyhow::Result<()> {
+        if let Some(written) = &mut self.written {
+            written.push(fields.clone());
+        }
+        let decimal_comma = self.decimal_comma;
+        self.output
+            .write_record(fields.into_iter().map(|field| match field {
+                Value::Null => String::new(),
+                Value::String(s) => s,
+                Value::Number(n) if decimal_comma && n.is_f64() => n.to_string().replace('.', ","),
+                field => field.to_string(),
+            }))
+            .context("failed to write record")
+    }
+}
+
+/// Writer of rows as a JSON array or as newline-delimited JSON objects
+struct JsonWriter<W> {
+    output: CountingWriter<W>,
+    /// Whether rows are elements of an array rather than one per line
+    array: bool,
+    pretty: bool,
+    /// Whether null fields are left out of rows
+    omit_null: bool,
+    /// Rows by billing code, written at the end with `--format grouped-json`
+    groups: Option<serde_json::Map<String, Value>>,
+    rows: u64,
+}
+
+impl<W: Write> JsonWriter<W> {
+    fn write(&mut self, mut row: serde_json::Map<String, Value>) -> anyhow::Result<()> {
+        if self.omit_null {
+            row.retain(|_, value| !value.is_null());
+        }
+        if let Some(groups) = &mut self.groups {
+            let code = match row.remove("billing_code") {
+                Some(Value::String(code)) => code,
+                _ => anyhow::bail!("failed to write record: row has no billing code"),
+            };
+            let group = groups
+                .entry(code)
+                .or_insert_with(|| Value::Array(Vec::new()));
+            if let Value::Array(rows) = group {
+                rows.push(row.into());
+            }
+            self.rows += 1;
+            return Ok(());
+        }
+        if self.array {
+            let separator: &[u8] = if self.rows == 0 { b"\n" } else { b",\n" };
+            self.output
+                .write_all(separator)
+                .context("failed to write record")?;
+        }
+        if self.pretty {
+            serde_json::to_writer_pretty(&mut self.output, &row)
+        } else {
+            serde_json::to_writer(&mut self.output, &row)
+        }
+        .context("failed to write record")?;
+        if !self.array {
+            self.output
+                .write_all(b"\n")
+                .context("failed to write record")?;
+        }
+        self.rows += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        if let Some(groups) = self.groups.take() {
+            if self.pretty {
+                serde_json::to_writer_pretty(&mut self.output, &groups)
+            } else {
+                serde_json::to_writer(&mut self.output, &groups)
+            }
+            .context("failed to write output")?;
+            self.output
+                .write_all(b"\n")
+                .context("failed to write output")?;
+        }
+        if self.array {
+            // Write the end whatever the limit, room having been reserved for it
+            self.output.flush().context("failed to write output")?;
+            self.output.limit = None;
+            let end: &[u8] = if self.rows == 0 {
+                &JSON_ARRAY_END[1..]
+            } else {
+                JSON_ARRAY_END
+            };
+            self.output
+                .write_all(end)
+                .context("failed to write output")?;
+        }
+        Ok(())
+    }
+}
+
+/// Writer of rows as a text table, with columns as wide as their widest field
+struct TableWriter<W> {
+    output: CountingWriter<W>,
+    header: Option<Vec<String>>,
+    /// Fields of the rows, with whether they are aligned to the right like numbers
+    rows: Vec<Vec<(String, bool)>>,
+    max_rows: u64,
+    /// Number of rows dropped beyond `max_rows`
+    dropped: u64,
+    quiet: bool,
+}
+
+impl<W: Write> TableWriter<W> {
+    fn write_object(&mut self, row: serde_json::Map<String, Value>) -> anyhow::Result<()> {
+        if self.header.is_none() {
+            self.header = Some(row.keys().cloned().collect());
+        }
+        self.write_fields(row.into_iter().map(|(_, field)| field).collect());
+        Ok(())
+    }
+
+    fn write_fields(&mut self, fields: Vec<Value>) {
+        if self.rows.len() as u64 >= self.max_rows {
+            self.dropped += 1;
+            return;
+        }
+        let row = fields
+            .into_iter()
+            .map(|field| match field {
+                Value::Null => (String::new(), false),
+                Value::String(s) => (s, false),
+                Value::Number(n) => (n.to_string(), true),
+                field => (field.to_string(), false),
+            })
+            .collect();
+        self.rows.push(row);
+    }
+
+    /// Writes the header, a line under it and the rows, with aligned columns
+    fn finish(&mut self) -> anyhow::Result<()> {
+        let header = self.header.take().unwrap_or_default();
+        let rows = std::mem::take(&mut self.rows);
+        let mut widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+        for row in &rows {
+            for (i, (field, _)) in row.iter().enumerate() {
+                let width = field.chars().count();
+                match widths.get_mut(i) {
+                    Some(w) => *w = (*w).max(width),
+                    None => widths.push(width),
+                }
+            }
+        }
+        let rule = widths.iter().map(|&w| ("-".repeat(w), false)).collect();
+        let header = header.into_iter().map(|h| (h, false)).collect();
+        for row in [header, rule].into_iter().chain(rows) {
+            let mut line = String::new();
+            for (i, (field, right)) in row.iter().enumerate() {
+                if i > 0 {
+                    line.push_str("  ");
+                }
+                let width = widths[i];
+                if *right {
+                    line.push_str(&format!("{field:>width$}"));
+                } else {
+                    line.push_str(&format!("{field:<width$}"));
+                }
+            }
+            writeln!(self.output, "{}", line.trim_end()).context("failed to write output")?;
+        }
+        if self.dropped > 0 && !self.quiet {
+            eprintln!(
+                "warning: {} rows beyond --table-max-rows {} were not written",
+                self.dropped, self.max_rows
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Writer of rows as record batches of an Arrow IPC stream
+struct ArrowWriter<W: Write> {
+    output: StreamWriter<CountingWriter<W>>,
+    schema: SchemaRef,
+    batch_size: usize,
+    names: StringBuilder,
+    billing_codes: StringBuilder,
+    avg_rates: Float64Builder,
+}
+
+impl<W: Write> ArrowWriter<W> {
+    /// Writes the schema of the stream to `output` and returns a writer of rows after it
+    fn new(
+        output: CountingWriter<W>,
+        resumed: bool,
+        options: &ProcessOptions,
+    ) -> anyhow::Result<Self> {
+        if resumed {
+            anyhow::bail!("resuming is not supported with --format arrow");
+        }
+        if options.max_output_bytes.is_some() {
+            anyhow::bail!("--max-output-bytes is not supported with --format arrow");
+        }
+        if options.columns() != ["name", "billing_code", "avg_rate"] {
+            anyhow::bail!(
+                "--format arrow only supports the name, billing_code and avg_rate columns"
+            );
+        }
+        let schema = Arc::new(Schema::new(vec![
+            arrow_schema::Field::new("name", DataType::Utf8, false),
+            arrow_schema::Field::new("billing_code", DataType::Utf8, false),
+            arrow_schema::Field::new("avg_rate", DataType::Float64, true),
+        ]));
+        Ok(Self {
+            output: StreamWriter::try_new(output, &schema).context("failed to write schema")?,
+            schema,
+            batch_size: options.arrow_batch_size.try_into().unwrap_or(usize::MAX),
+            names: StringBuilder::new(),
+            billing_codes: StringBuilder::new(),
+            avg_rates: Float64Builder::new(),
+        })
+    }
+
+    /// Adds `row` to the current batch, writing the batch if it is full
+    fn write(&mut self, row: serde_json::Map<String, Value>) -> anyhow::Result<()> {
+        let text = |field| row.get(field).and_then(Value::as_str).unwrap_or_default();
+        self.names.append_value(text("name"));
+        self.billing_codes.append_value(text("billing_code"));
+        self.avg_rates
+            .append_option(row.get("avg_rate").and_then(Value::as_f64));
+        if self.names.len() >= self.batch_size {
+            self.write_batch()?;
+        }
+        Ok(())
+    }
+
+    fn write_batch(&mut self) -> anyhow::Result<()> {
+        if self.names.is_empty() {
+            return Ok(());
+        }
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.names.finish()),
+            Arc::new(self.billing_codes.finish()),
+            Arc::new(self.avg_rates.finish()),
+        ];
+        let batch =
+            RecordBatch::try_new(self.schema.clone(), columns).context("failed to write record")?;
+        self.output.write(&batch).context("failed to write record")
+    }
+
+    /// Writes the current batch, even if not full, and flushes
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.write_batch()?;
+        self.output.flush().context("failed to write output")
+    }
+
+    /// Writes the current batch and the end of the stream
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.write_batch()?;
+        self.output.finish().context("failed to write output")
+    }
+}
+
+/// End of a JSON array of rows
+const JSON_ARRAY_END: &[u8] = b"\n]\n";
+
+/// Writer wrapper counting the bytes written to the inner writer
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+    limit: Option<Limit>,
+}
+
+/// Maximum number of bytes to write, bytes being staged until flushed so that only whole rows are
+/// written
+struct Limit {
+    max_bytes: u64,
+    staged: Vec<u8>,
+    /// Whether staged bytes were dropped, after which all bytes are
+    reached: bool,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(limit) = &mut self.limit {
+            if !limit.reached {
+                limit.staged.extend_from_slice(buf);
+            }
+            return Ok(buf.len());
+        }
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    /// Writes the staged bytes if they fit within the limit, dropping them otherwise, and flushes
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(limit) = &mut self.limit {
+            let fits = self.count + limit.staged.len() as u64 <= limit.max_bytes;
+            if fits && !limit.reached {
+                self.inner.write_all(&limit.staged)?;
+                self.count += limit.staged.len() as u64;
+            } else {
+                limit.reached = true;
+            }
+            limit.staged.clear();
+        }
+        self.inner.flush()
+    }
+}
+
+/// Returns the expected range of average rates of each billing code with one
+fn expected_ranges(options: &ProcessOptions) -> anyhow::Result<HashMap<String, ExpectedRange>> {
+    let mut ranges = Vec::new();
+    if let Some(p) = &options.expect_ranges_file {
+        let contents =
+            fs::read_to_string(p).with_context(|| format!("failed to read {}", p.display()))?;
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if !line.is_empty() {
+                let range = line.parse::<ExpectedRange>().with_context(|| {
+                    format!("invalid range on line {} of {}", i + 1, p.display())
+                })?;
+                ranges.push(range);
+            }
+        }
+    }
+    ranges.extend(options.expect_range.iter().cloned());
+    Ok(ranges
+        .into_iter()
+        .map(|range| {
+            let code = options
+                .code_normalize
+                .apply(&range.billing_code)
+                .into_owned();
+            (code, range)
+        })
+        .collect())
+}
+
+/// Row of a previous output read with `--baseline`
+#[derive(Deserialize)]
+struct BaselineRow {
+    billing_code: String,
+    avg_rate: Option<String>,
+}
+
+/// Returns the average rate of each billing code in the `--baseline` output, if any
+fn baseline(options: &ProcessOptions) -> anyhow::Result<Option<HashMap<String, Option<f64>>>> {
+    let Some(p) = &options.baseline else {
+        return Ok(None);
+    };
+    let comment = u8::try_from(options.comment_char).ok();
+    let rate = |rate: &str| -> anyhow::Result<f64> {
+        let rate = if options.decimal_comma {
+            Cow::Owned(rate.replace(',', "."))
+        } else {
+            Cow::Borrowed(rate)
+        };
+        rate.parse::<f64>()
+            .with_context(|| format!("invalid avg_rate `{rate}`"))
+    };
+    let read = || -> anyhow::Result<_> {
+        let reader = csv::ReaderBuilder::new()
+            .comment(comment)
+            .delimiter(output_delimiter(options)?)
+            .from_path(p)?;
+        reader
+            .into_deserialize::<BaselineRow>()
+            .map(|row| {
+                let row = row?;
+                Ok((
+                    row.billing_code,
+                    row.avg_rate.as_deref().map(rate).transpose()?,
+                ))
+            })
+            .collect()
+    };
+    let rows = read().with_context(|| format!("failed to read {}", p.display()))?;
+    Ok(Some(rows))
+}
+
+/// Returns the rate of `record`'s billing code in `baseline` and how much it changed, both null
+/// if the code is new, or `None` if it changed by `--min-delta` or less
+///
+/// With `--scale`, the rate of `record` is compared as written, the baseline holding scaled and
+/// rounded rates, and both values are returned scaled.
+fn rate_change(
+    baseline: &HashMap<String, Option<f64>>,
+    record: &Record,
+    options: &ProcessOptions,
+) -> Option<(Value, Value)> {
+    let Some(&prev_rate) = baseline.get(&record.billing_code) else {
+        return Some((Value::Null, Value::Null));
+    };
+    let (rate, min_delta, value): (_, _, fn(f64) -> Value) = match options.scale {
+        Some(scale) => (
+            options.rate_value(record.avg_rate).as_f64(),
+            options.min_delta * scale,
+            |rate| (rate as i64).into(),
+        ),
+        None => (record.avg_rate, options.min_delta, Value::from),
+    };
+    match (prev_rate, rate) {
+        (Some(prev_rate), Some(rate)) => {
+            let delta = rate - prev_rate;
+            (delta.abs() > min_delta).then(|| (value(prev_rate), value(delta)))
+        }
+        (None, None) => None,
+        (prev_rate, _) => Some((prev_rate.map(value).into(), Value::Null)),
+    }
+}
+
+/// Returns the billing codes to keep, or `None` if records are not filtered by code
+fn billing_codes(options: &ProcessOptions) -> anyhow::Result<Option<HashSet<String>>> {
+    let mut codes = options.billing_code.iter().cloned().collect::<HashSet<_>>();
+    if let Some(p) = &options.billing_codes_file {
+        let contents =
+            fs::read_to_string(p).with_context(|| format!("failed to read {}", p.display()))?;
+        codes.extend(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|code| !code.is_empty())
+                .map(Into::into),
+        );
+    } else if codes.is_empty() {
+        return Ok(None);
+    }
+    let normalize = options.code_normalize;
+    Ok(Some(
+        codes
+            .iter()
+            .map(|code| normalize.apply(code).into_owned())
+            .collect(),
+    ))
+}
+
+/// Reads the billing codes of `--expected-codes`, normalized, in the order of the file
+fn expected_codes(options: &ProcessOptions) -> anyhow::Result<Option<Vec<String>>> {
+    let Some(p) = &options.expected_codes else {
+        return Ok(None);
+    };
+    let contents =
+        fs::read_to_string(p).with_context(|| format!("failed to read {}", p.display()))?;
+    let mut seen = HashSet::new();
+    Ok(Some(
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|code| !code.is_empty())
+            .map(|code| options.code_normalize.apply(code).into_owned())
+            .filter(|code| seen.insert(code.clone()))
+            .collect(),
+    ))
+}
+
+/// Maximum size of the memory of a `--filter-wasm` module, in bytes
+#[cfg(feature = "filter-wasm")]
+const FILTER_WASM_MAX_MEMORY: usize = 64 << 20;
+
+/// Fuel a `--filter-wasm` module is given to instantiate and then to filter each record, bounding
+/// the number of instructions it runs
+#[cfg(feature = "filter-wasm")]
+const FILTER_WASM_FUEL: u64 = 10_000_000;
+
+/// Record filter loaded from a `--filter-wasm` module
+///
+/// The module is instantiated without any import, so it can only compute on the records it is
+/// given, within [`FILTER_WASM_MAX_MEMORY`] bytes of memory and [`FILTER_WASM_FUEL`] per record.
+#[cfg(feature = "filter-wasm")]
+struct WasmFilter {
+    store: wasmtime::Store<wasmtime::StoreLimits>,
+    memory: wasmtime::Memory,
+    alloc: wasmtime::TypedFunc<i32, i32>,
+    keep: wasmtime::TypedFunc<(i32, i32), i32>,
+}
+
+#[cfg(feature = "filter-wasm")]
+impl WasmFilter {
+    /// Compiles and instantiates the module at `path`, in binary or text format
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = wasmtime::Engine::new(&config)?;
+        let module = wasmtime::Module::from_file(&engine, path)
+            .map_err(anyhow::Error::from)
+            .with_context(|| format!("failed to load {}", path.display()))?;
+        let limits = wasmtime::StoreLimitsBuilder::new()
+            .memory_size(FILTER_WASM_MAX_MEMORY)
+            .instances(1)
+            .build();
+        let mut store = wasmtime::Store::new(&engine, limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(FILTER_WASM_FUEL)?;
+        let instance = wasmtime::Instance::new(&mut store, &module, &[])
+            .map_err(anyhow::Error::from)
+            .with_context(|| format!("failed to instantiate {}", path.display()))?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .with_context(|| format!("{} doesn't export its memory", path.display()))?;
+        let alloc = instance
+            .get_typed_func(&mut store, "alloc")
+            .map_err(anyhow::Error::from)
+            .with_context(|| format!("{} doesn't export alloc(i32) -> i32", path.display()))?;
+        let keep = instance
+            .get_typed_func(&mut store, "keep")
+            .map_err(anyhow::Error::from)
+            .with_context(|| format!("{} doesn't export keep(i32, i32) -> i32", path.display()))?;
+        Ok(Self {
+            store,
+            memory,
+            alloc,
+            keep,
+        })
+    }
+
+    /// Tells whether the module keeps `record`
+    fn keep(&mut self, record: &Record) -> anyhow::Result<bool> {
+        let json = serde_json::to_vec(record)?;
+        let len = i32::try_from(json.len()).context("record is too large for --filter-wasm")?;
+        self.store.set_fuel(FILTER_WASM_FUEL)?;
+        let ptr = self
+            .alloc
+            .call(&mut self.store, len)
+            .map_err(anyhow::Error::from)
+            .context("--filter-wasm failed to allocate memory")?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, &json)
+            .context("--filter-wasm allocated memory out of bounds")?;
+        let keep = self
+            .keep
+            .call(&mut self.store, (ptr, len))
+            .map_err(anyhow::Error::from)
+            .context("--filter-wasm failed to filter the record")?;
+        Ok(keep != 0)
+    }
+}
+
+/// Stand-in for the `--filter-wasm` filter when built without the `filter-wasm` feature
+#[cfg(not(feature = "filter-wasm"))]
+enum WasmFilter {}
+
+#[cfg(not(feature = "filter-wasm"))]
+impl WasmFilter {
+    fn load(_: &Path) -> anyhow::Result<Self> {
+        anyhow::bail!("--filter-wasm requires building with the filter-wasm feature")
+    }
+
+    fn keep(&mut self, _: &Record) -> anyhow::Result<bool> {
+        match *self {}
+    }
+}
+
+/// Formats which of the `expected` billing codes are `present` in the output, listing the missing
+/// ones
+fn coverage_report(expected: &[String], present: &HashSet<String>) -> String {
+    let missing = expected
+        .iter()
+        .filter(|code| !present.contains(*code))
+        .collect::<Vec<_>>();
+    let mut report = format!(
+        "expected billing codes: {} of {} present\n",
+        expected.len() - missing.len(),
+        expected.len()
+    );
+    for code in missing {
+        report += &format!("missing: {code}\n");
+    }
+    report
+}
+
+/// Parses each line of `input` into a record and the rates it aggregates
+///
+/// Items are `Err` when a line cannot be read or parsed, in which case reading may continue with
+/// the next line.
+pub fn records<I>(
+    input: I,
+    options: &ProcessOptions,
+) -> impl Iterator<Item = anyhow::Result<(Record, AccumulatedRate)>>
+where
+    I: BufRead,
+{
+    numbered_records(input, None, options).map(|(_, record)| record)
+}
+
+/// Like [`records`], but also yields the number of the line each record was parsed from, counting
+/// the lines skipped with `--allow-comments` in `skipped`
+fn numbered_records<I>(
+    input: I,
+    skipped: Option<&SkippedLines>,
+    options: &ProcessOptions,
+) -> impl Iterator<Item = (usize, anyhow::Result<(Record, AccumulatedRate)>)>
+where
+    I: BufRead,
+{
+    let max_records = options
+        .max_records
+        .map_or(usize::MAX, |n| n.try_into().unwrap_or(usize::MAX));
+    // Only one of the iterators is created, chained to the other to get a single iterator type
+    let (jsonl, csv) = match options.input_format {
+        InputFormat::Jsonl => (
+            Some(jsonl_records(input, max_records, skipped, options)),
+            None,
+        ),
+        InputFormat::Csv => (None, Some(csv_records(input, options).take(max_records))),
+    };
+    jsonl.into_iter().flatten().chain(csv.into_iter().flatten())
+}
+
+/// Parses the records on the first `max_lines` lines of `input`
+fn jsonl_records<I>(
+    input: I,
+    max_lines: usize,
+    skipped: Option<&SkippedLines>,
+    options: &ProcessOptions,
+) -> impl Iterator<Item = (usize, anyhow::Result<(Record, AccumulatedRate)>)>
+where
+    I: BufRead,
+{
+    lines(input, options)
+        .take(max_lines)
+        .enumerate()
+        .filter(move |(_, line)| {
+            let kind = line.as_ref().ok().and_then(|l| SkippedLine::of(l, options));
+            if let (Some(kind), Some(skipped)) = (kind, skipped) {
+                skipped.add(kind);
+            }
+            kind.is_none()
+        })
+        .flat_map(|(i, line)| {
+            let records = line.context("failed to read line").and_then(|line| {
+                timed(Stage::Parse, || parse_records(&line, options))
+                    .context("failed to parse record")
+            });
+            match records {
+                Ok(records) => records.into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(e)],
+            }
+            .into_iter()
+            .map(move |record| (i + 1, record))
+        })
+}
+
+/// Reads records from CSV, each counting as a single rate equal to its average rate
+fn csv_records<I>(
+    input: I,
+    options: &ProcessOptions,
+) -> impl Iterator<Item = (usize, anyhow::Result<(Record, AccumulatedRate)>)>
+where
+    I: BufRead,
+{
+    let mut records =
+        csv::Reader::from_reader(Profiled::new(input, Stage::Read)).into_deserialize::<Record>();
+    std::iter::from_fn(move || timed(Stage::Parse, || records.next()))
+        .enumerate()
+        .map(|(i, record)| {
+            let record = record.context("failed to parse record").map(|record| {
+                let mut rate = AccumulatedRate::new(options);
+                if let Some(negotiated_rate) = record.avg_rate {
+                    rate = rate + NegotiatedPrice { negotiated_rate };
+                }
+                (record, rate)
+            });
+            // Line 1 is the header
+            (i + 2, record)
+        })
+}
+
+/// Parses the record on `line`, or all the records concatenated on it with
+/// `--multi-object-lines`
+fn parse_records(
+    line: &str,
+    options: &ProcessOptions,
+) -> serde_json::Result<Vec<(Record, AccumulatedRate)>> {
+    let mut deserializer = serde_json::Deserializer::from_str(line);
+    let mut records = vec![parse_object(&mut deserializer, options)?];
+    if options.multi_object_lines {
+        while deserializer.end().is_err() {
+            records.push(parse_object(&mut deserializer, options)?);
+        }
+    }
+    deserializer.end()?;
+    Ok(records)
+}
+
+/// Parses the next object of `deserializer` into a record, resolving the `--project` projections
+/// in it
+fn parse_object<'de, D>(
+    deserializer: D,
+    options: &ProcessOptions,
+) -> serde_json::Result<(Record, AccumulatedRate)>
+where
+    D: Deserializer<'de, Error = serde_json::Error>,
+{
+    let _deadline = options.per_record_timeout.map(RecordDeadline::start);
+    let enriched = options.format == OutputFormat::EnrichedJson;
+    if options.project.is_empty() && !enriched {
+        return RecordSeed { options }.deserialize(deserializer);
+    }
+    // Projections can point anywhere, so go through a JSON value instead of streaming
+    let (object, (mut record, rate)) = if options.strict_numbers {
+        // The rates of a JSON value have lost their literals, so parse them from the text
+        let text = Box::<RawValue>::deserialize(deserializer)?;
+        let parsed = RecordSeed { options }
+            .deserialize(&mut serde_json::Deserializer::from_str(text.get()))?;
+        (serde_json::from_str(text.get())?, parsed)
+    } else {
+        let object = Value::deserialize(deserializer)?;
+        let parsed = RecordSeed { options }.deserialize(&object)?;
+        (object, parsed)
+    };
+    record.projected = options
+        .project
+        .iter()
+        .map(|p| object.pointer(&p.pointer).cloned().unwrap_or_default())
+        .collect();
+    if let (Value::Object(object), true) = (object, enriched) {
+        record.source = Some(object);
+    }
+    Ok((record, rate))
+}
+
+/// Reads lines like `BufRead::lines`, retrying on transient errors as configured in `options`
+///
+/// Bytes read before an error are kept so that a retried line is not truncated.
+fn lines<I>(mut input: I, options: &ProcessOptions) -> impl Iterator<Item = io::Result<String>>
+where
+    I: BufRead,
+{
+    let retries = options.read_retries;
+    let quiet = options.quiet;
+    std::iter::from_fn(move || {
+        let mut line = Vec::new();
+        let mut attempt = 0;
+        loop {
+            match timed(Stage::Read, || input.read_until(b'\n', &mut line)) {
+                Ok(0) if line.is_empty() => return None,
+                Ok(_) => break,
+                Err(e) if attempt < retries && is_transient(&e) => {
+                    if !quiet {
+                        eprintln!("warning: retrying read after error: {e}");
+                    }
+                    thread::sleep(RETRY_BASE_DELAY * 2u32.pow(attempt.min(6)));
+                    attempt += 1;
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        if line.last() == Some(&b'\n') {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        }
+        Some(String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+    })
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        AccumulatedRate, Checkpoint, Checkpointer, ErrorCategory, ExpectedRange, FieldMapping,
+        HyperLogLog, Inputs, NameNormalization, NegotiatedPrice, NonFiniteMinMax, OffsetReader,
+        ProcessOptions, Profiling, ProgressFormat, Projection, Record, RecordSeed, Resume,
+        RowWriter, Shard, Stage, Summary, combine_csv, coverage_report, error_report,
+        expected_codes, group_size_histogram, lines, parse_duration, probe, process,
+        process_checkpointed, process_inputs, process_parallel, process_parallel_prefetched,
+        process_split, process_verified, process_with, profile_report, progress_line, records,
+        timed, write_config, write_schema,
+    };
+    use arrow_array::{Array, Float64Array};
+    use arrow_ipc::reader::StreamReader;
+    use clap::Parser;
+    use serde::de::DeserializeSeed;
+    use serde_json::json;
+    use std::{
+        cell::{Cell, RefCell},
+        collections::BTreeMap,
+        fs,
+        io::{self, BufReader, Read, Write},
+        num::NonZeroUsize,
+        rc::Rc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        options: ProcessOptions,
+    }
+
+    fn options(args: &[&str]) -> ProcessOptions {
+        Cli::parse_from(std::iter::once("etl").chain(args.iter().copied())).options
+    }
+
+    fn run(input: &str, args: &[&str]) -> anyhow::Result<String> {
+        let mut output = Vec::new();
+        process(input.as_bytes(), &mut output, &options(args))?;
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    fn line(name: &str, billing_code: &str, rates: &[f64]) -> String {
+        let prices = rates
+            .iter()
+            .map(|rate| json!({ "negotiated_rate": rate }))
+            .collect::<Vec<_>>();
+        let record = json!({
+            "name": name,
+            "billing_code": billing_code,
+            "negotiated_rates": [{ "negotiated_prices": prices }],
+        });
+        format!("{record}\n")
+    }
+
+    /// Reader failing with a transient error once before yielding each chunk
+    struct FlakyReader {
+        chunks: Vec<&'static [u8]>,
+        failed: bool,
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.chunks.is_empty() {
+                return Ok(0);
+            }
+            if !self.failed {
+                self.failed = true;
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.failed = false;
+            let chunk = self.chunks.remove(0);
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    #[test]
+    fn average_is_calculated() {
+        let input = json!({
+            "name": "alpha",
+            "billing_code": "1",
+            "negotiated_rates": [
+                {
+                    "negotiated_prices": [
+                        {
+                            "negotiated_rate": 10,
+                        },
+                    ],
+                },
+                {
+                    "negotiated_prices": [],
+                },
+                {
+                    "negotiated_prices": [
+                        {
+                            "negotiated_rate": 20,
+                        },
+                        {
+                            "negotiated_rate": 60,
+                        },
+                    ],
+                },
+            ],
+        });
+
+        let expected = Record {
+            name: "alpha".into(),
+            billing_code: "1".into(),
+            avg_rate: Some(30.0),
+            billing_code_type: None,
+            passthrough: Vec::new(),
+            projected: Vec::new(),
+            rates: Vec::new(),
+            rate_frequencies: Vec::new(),
+            source: None,
+            date: None,
+        };
+
+        let actual = RecordSeed {
+            options: &options(&[]),
+        }
+        .deserialize(input)
+        .unwrap()
+        .0;
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn average_is_none_when_no_rates() {
+        let input = json!({
+            "name": "alpha",
+            "billing_code": "1",
+            "negotiated_rates": [],
+        });
+
+        let actual = RecordSeed {
+            options: &options(&[]),
+        }
+        .deserialize(input)
+        .unwrap()
+        .0;
+        assert_eq!(actual.avg_rate, None);
+    }
+
+    #[test]
+    fn rates_are_read_from_configured_field() {
+        let input = json!({
+            "name": "alpha",
+            "billing_code": "1",
+            "negotiated_rates": [],
+            "in_network": [
+                {
+                    "negotiated_prices": [
+                        {
+                            "negotiated_rate": 10,
+                        },
+                    ],
+                },
+            ],
+        });
+
+        let actual = RecordSeed {
+            options: &options(&["--rates-field", "in_network"]),
+        }
+        .deserialize(input)
+        .unwrap()
+        .0;
+        assert_eq!(actual.avg_rate, Some(10.0));
+    }
+
+    #[test]
+    fn missing_rates_field_is_an_error() {
+        let input = json!({
+            "name": "alpha",
+            "billing_code": "1",
+            "negotiated_rates": [],
+        });
+
+        let error = RecordSeed {
+            options: &options(&["--rates-field", "rates"]),
+        }
+        .deserialize(input)
+        .unwrap_err();
+        assert_eq!(error.to_string(), "missing field `rates`");
+    }
+
+    #[test]
+    fn transient_read_errors_are_retried() {
+        let input = BufReader::new(FlakyReader {
+            chunks: vec![b"fir", b"st\nsecond\n"],
+            failed: false,
+        });
+        let actual = lines(input, &options(&["--read-retries", "2", "--quiet"]))
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(actual, ["first", "second"]);
+    }
+
+    #[test]
+    fn transient_read_errors_fail_without_retries() {
+        let input = BufReader::new(FlakyReader {
+            chunks: vec![b"first\n"],
+            failed: false,
+        });
+        let error = lines(input, &options(&[])).next().unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn records_are_filtered_by_billing_code() {
+        let input = [
+            line("alpha", "1", &[10.0]),
+            line("beta", "2", &[10.0]),
+            line("gamma", "3", &[10.0]),
+        ]
+        .concat();
+        let actual = run(&input, &["--billing-code", "1", "--billing-code", "3"]).unwrap();
+        assert_eq!(
+            actual,
+            "name,billing_code,avg_rate\nalpha,1,10.0\ngamma,3,10.0\n"
+        );
+    }
+
+    #[test]
+    fn expect_stops_reading_after_enough_matches() {
+        let input = [line("alpha", "1", &[10.0]), "not json\n".into()].concat();
+        let actual = run(&input, &["--billing-code", "1", "--expect", "1"]).unwrap();
+        assert_eq!(actual, "name,billing_code,avg_rate\nalpha,1,10.0\n");
+        let input = [line("a", "1", &[100.0]), line("b", "2", &[1.0]), input].concat();
+        let args = [
+            "--billing-code",
+            "1,2",
+            "--max-rate",
+            "5",
+            "--include-rejected",
+        ];
+        let actual = run(&input, &[&args[..], &["--expect", "1"]].concat()).unwrap();
+        assert_eq!(actual, "name,billing_code,avg_rate\na,1,100.0\nb,2,1.0\n");
+    }
+
+    /// Runs with `--filter-wasm` set to a module written from its text format `wat`
+    #[cfg(feature = "filter-wasm")]
+    fn run_wasm_filter(name: &str, wat: &str, input: &str) -> anyhow::Result<String> {
+        let path = std::env::temp_dir().join(format!("etl-{name}-{}.wat", std::process::id()));
+        fs::write(&path, wat).unwrap();
+        let actual = run(input, &["--filter-wasm", path.to_str().unwrap()]);
+        fs::remove_file(&path).unwrap();
+        actual
+    }
+
+    #[cfg(feature = "filter-wasm")]
+    #[test]
+    fn always_true_wasm_filter_keeps_every_record() {
+        let wat = r#"(module
+            (memory (export "memory") 1)
+            (func (export "alloc") (param i32) (result i32) i32.const 0)
+            (func (export "keep") (param i32 i32) (result i32) i32.const 1))"#;
+        let input = [line("alpha", "1", &[10.0]), line("beta", "2", &[20.0])].concat();
+        let actual = run_wasm_filter("always-true", wat, &input).unwrap();
+        assert_eq!(actual, run(&input, &[]).unwrap());
+    }
+
+    #[cfg(feature = "filter-wasm")]
+    #[test]
+    fn wasm_filter_is_given_records_as_json() {
+        // Keeps records whose name starts with "a", right after `{"name":"`
+        let wat = r#"(module
+            (memory (export "memory") 1)
+            (func (export "alloc") (param i32) (result i32) i32.const 16)
+            (func (export "keep") (param $ptr i32) (param $len i32) (result i32)
+                (i32.eq (i32.load8_u offset=9 (local.get $ptr)) (i32.const 97))))"#;
+        let input = [line("alpha", "1", &[10.0]), line("beta", "2", &[20.0])].concat();
+        let actual = run_wasm_filter("json", wat, &input).unwrap();
+        assert_eq!(actual, "name,billing_code,avg_rate\nalpha,1,10.0\n");
+    }
+
+    #[cfg(feature = "filter-wasm")]
+    #[test]
+    fn wasm_filter_runs_out_of_fuel() {
+        let wat = r#"(module
+            (memory (export "memory") 1)
+            (func (export "alloc") (param i32) (result i32) i32.const 0)
+            (func (export "keep") (param i32 i32) (result i32) (loop (br 0)) i32.const 1))"#;
+        let e = run_wasm_filter("fuel", wat, &line("alpha", "1", &[10.0])).unwrap_err();
+        assert!(format!("{e:#}").contains("fuel"), "{e:#}");
+    }
+
+    #[cfg(feature = "filter-wasm")]
+    #[test]
+    fn wasm_filter_cannot_import_anything() {
+        let wat = r#"(module
+            (import "env" "read" (func (param i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "alloc") (param i32) (result i32) i32.const 0)
+            (func (export "keep") (param i32 i32) (result i32) i32.const 1))"#;
+        let result = run_wasm_filter("import", wat, &line("alpha", "1", &[10.0]));
+        assert!(result.is_err());
+    }
+
+    #[cfg(not(feature = "filter-wasm"))]
+    #[test]
+    fn filter_wasm_requires_the_feature() {
+        let result = run(
+            &line("alpha", "1", &[10.0]),
+            &["--filter-wasm", "filter.wasm"],
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn expect_requires_billing_code_filter() {
+        let result = Cli::try_parse_from(["etl", "--expect", "1"]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn summary_counts_read_and_written_records() {
+        let input = [line("alpha", "1", &[10.0]), line("beta", "2", &[40.0])].concat();
+        let summary = process(input.as_bytes(), io::sink(), &options(&[])).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                read: 2,
+                written: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn comments_are_written_before_header() {
+        let input = line("alpha", "1", &[10.0]);
+        let args = [
+            "--output-comment",
+            "source: sample",
+            "--output-comment",
+            "threshold: 30\nrun: 1",
+            "--comment-char",
+            ";",
+        ];
+        let actual = run(&input, &args).unwrap();
+        let expected =
+            ";source: sample\n;threshold: 30\n;run: 1\nname,billing_code,avg_rate\nalpha,1,10.0\n";
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn rate_thresholds_are_configurable() {
+        let input = [
+            line("alpha", "1", &[5.0]),
+            line("beta", "2", &[15.0]),
+            line("gamma", "3", &[25.0]),
+        ]
+        .concat();
+        let actual = run(&input, &["--min-rate", "10", "--max-rate", "20"]).unwrap();
+        assert_eq!(actual, "name,billing_code,avg_rate\nbeta,2,15.0\n");
+    }
+
+    #[test]
+    fn status_is_annotated_for_every_record() {
+        let input = [
+            line("alpha", "1", &[5.0]),
+            line("beta", "2", &[15.0]),
+            line("gamma", "3", &[40.0]),
+            line("delta", "4", &[]),
+        ]
+        .concat();
+        let args = [
+            "--min-rate",
+            "10",
+            "--include-rejected",
+            "--annotate-status",
+        ];
+        let actual = run(&input, &args).unwrap();
+        let expected = "\
+            name,billing_code,avg_rate,status\n\
+            alpha,1,5.0,rejected_low\n\
+            beta,2,15.0,kept\n\
+            gamma,3,40.0,rejected_high\n\
+            delta,4,,rejected_null\n";
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn average_only_includes_max_prices_per_group() {
+        let input = json!({
+            "name": "alpha",
+            "billing_code": "1",
+            "negotiated_rates": [
+                {
+                    "negotiated_prices": [
+                        {
+                            "negotiated_rate": 10,
+                        },
+                        {
+                            "negotiated_rate": 20,
+                        },
+                        {
+                            "negotiated_rate": 60,
+                        },
+                    ],
+                },
+                {
+                    "negotiated_prices": [
+                        {
+                            "negotiated_rate": 30,
+                        },
+                    ],
+                },
+            ],
+        });
+
+        let actual = RecordSeed {
+            options: &options(&["--max-prices-per-group", "2"]),
+        }
+        .deserialize(input)
+        .unwrap()
+        .0;
+        assert_eq!(actual.avg_rate, Some(20.0));
+    }
+
+    #[test]
+    fn prices_are_exploded_into_rows() {
+        let input = [
+            line("alpha", "1", &[10.0, 40.0, 20.0]),
+            line("beta", "2", &[]),
+        ]
+        .concat();
+        let actual = run(&input, &["--explode-prices"]).unwrap();
+        let expected = "\
+            name,billing_code,negotiated_rate\n\
+            alpha,1,10.0\n\
+            alpha,1,20.0\n";
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn summation_is_compensated() {
+        let prices = vec![0.1; 1000];
+        let naive = prices.iter().sum::<f64>() / prices.len() as f64;
+        assert_ne!(naive, 0.1);
+
+        let acc = prices
+            .into_iter()
+            .fold(AccumulatedRate::default(), |acc, negotiated_rate| {
+                acc + NegotiatedPrice { negotiated_rate }
+            });
+        assert_eq!(acc.average(), Some(0.1));
+    }
+
+    #[test]
+    fn summation_survives_cancellation() {
+        let input = [line("alpha", "1", &[1e17, 1.0, -1e17, 1.0])].concat();
+        let actual = run(&input, &[]).unwrap();
+        assert_eq!(actual, "name,billing_code,avg_rate\nalpha,1,0.5\n");
+    }
+
+    #[test]
+    fn null_rate_records_are_only_kept_on_request() {
+        let input = [line("alpha", "1", &[]), line("beta", "2", &[10.0])].concat();
+        let actual = run(&input, &[]).unwrap();
+        assert_eq!(actual, "name,billing_code,avg_rate\nbeta,2,10.0\n");
+        let actual = run(&input, &["--include-null-rate", "--min-rate", "5"]).unwrap();
+        assert_eq!(
+            actual,
+            "name,billing_code,avg_rate\nalpha,1,\nbeta,2,10.0\n"
+        );
+    }
+
+    #[test]
+    fn rate_thresholds_are_ignored_without_filter() {
+        let input = [
+            line("alpha", "1", &[10.0]),
+            line("beta", "2", &[50.0]),
+            line("gamma", "3", &[]),
+        ]
+        .concat();
+        assert_eq!(
+            run(&input, &["--no-filter"]).unwrap(),
+            "name,billing_code,avg_rate\nalpha,1,10.0\nbeta,2,50.0\n"
+        );
+        assert_eq!(
+            run(&input, &["--no-filter", "--include-null-rate"]).unwrap(),
+            "name,billing_code,avg_rate\nalpha,1,10.0\nbeta,2,50.0\ngamma,3,\n"
+        );
+    }
+
+    #[test]
+    fn kept_records_are_counted_per_billing_code() {
+        let input = [
+            line("alpha", "2", &[10.0]),
+            line("beta", "1", &[10.0]),
+            line("gamma", "2", &[20.0]),
+            line("delta", "3", &[40.0]),
+        ]
+        .concat();
+        let actual = run(&input, &["--count-only"]).unwrap();
+        assert_eq!(actual, "billing_code,count\n1,1\n2,2\n");
+    }
+
+    #[test]
+    fn sorted_input_is_counted_group_by_group() {
+        let input = [
+            line("alpha", "1", &[10.0]),
+            line("beta", "1", &[50.0]),
+            line("gamma", "2", &[20.0]),
+            line("delta", "3", &[10.0]),
+        ]
+        .concat();
+        let args = ["--count-only", "--assume-sorted"];
+        let expected = run(&input, &["--count-only"]).unwrap();
+        assert_eq!(run(&input, &args).unwrap(), expected);
+        let unsorted = [line("alpha", "2", &[10.0]), line("beta", "1", &[10.0])].concat();
+        let e = run(&unsorted, &args).unwrap_err();
+        assert_eq!(e.to_string(), "input is not sorted by key: 1 follows 2");
+    }
+
+    #[test]
+    fn records_are_counted_per_composite_key() {
+        let typed_line = |billing_code_type: &str| {
+            let mut record =
+                serde_json::from_str::<serde_json::Value>(&line("alpha", "1", &[10.0])).unwrap();
+            record["billing_code_type"] = billing_code_type.into();
+            format!("{record}\n")
+        };
+        let input = [typed_line("CPT"), typed_line("HCPCS"), typed_line("CPT")].concat();
+        let actual = run(
+            &input,
+            &["--count-only", "--key", "billing_code,billing_code_type"],
+        )
+        .unwrap();
+        assert_eq!(
+            actual,
+            "billing_code,billing_code_type,count\n1,CPT,2\n1,HCPCS,1\n"
+        );
+    }
+
+    #[test]
+    fn default_options_match_command_line_defaults() {
+        assert_eq!(ProcessOptions::default(), options(&[]));
+    }
+
+    #[test]
+    fn records_expose_accumulated_rates() {
+        let input = [
+            line("alpha", "1", &[10.0, 40.0, 25.0]),
+            line("beta", "2", &[]),
+        ]
+        .concat();
+        let records = records(input.as_bytes(), &ProcessOptions::default())
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        let [(alpha, alpha_rate), (beta, beta_rate)] = &records[..] else {
+            panic!("expected two records");
+        };
+        assert_eq!(alpha.avg_rate, Some(25.0));
+        assert_eq!(alpha_rate.sum(), 75.0);
+        assert_eq!(alpha_rate.count(), 3);
+        assert_eq!(alpha_rate.min(), Some(10.0));
+        assert_eq!(alpha_rate.max(), Some(40.0));
+        assert_eq!(beta.avg_rate, None);
+        assert_eq!(beta_rate.count(), 0);
+        assert_eq!(beta_rate.min(), None);
+    }
+
+    #[test]
+    fn names_are_normalized() {
+        let name = || " ÉCOLE de l'été\t".to_owned();
+        assert_eq!(NameNormalization::None.apply(name()), name());
+        assert_eq!(NameNormalization::Trim.apply(name()), "ÉCOLE de l'été");
+        assert_eq!(NameNormalization::Lower.apply(name()), " école de l'été\t");
+        assert_eq!(NameNormalization::Upper.apply(name()), " ÉCOLE DE L'ÉTÉ\t");
+        assert_eq!(NameNormalization::Title.apply(name()), " École De L'Été\t");
+    }
+
+    /// Writer appending to a buffer shared with the test
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn output_is_split_with_header_in_each_part() {
+        let input = [
+            line("alpha", "1", &[10.0]),
+            line("beta", "2", &[10.0]),
+            line("gamma", "3", &[10.0]),
+        ]
+        .concat();
+        let parts = RefCell::new(Vec::new());
+        let open = |part| {
+            assert_eq!(part, parts.borrow().len() as u64);
+            let buffer = Rc::new(RefCell::new(Vec::new()));
+            parts.borrow_mut().push(buffer.clone());
+            Ok(SharedBuffer(buffer))
+        };
+        let options = options(&["--output-comment", "part"]);
+        process_split(input.as_bytes(), 40, open, &options).unwrap();
+        let parts = parts
+            .into_inner()
+            .into_iter()
+            .map(|part| String::from_utf8(part.take()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            parts,
+            [
+                "#part\nname,billing_code,avg_rate\nalpha,1,10.0\n",
+                "#part\nname,billing_code,avg_rate\nbeta,2,10.0\n",
+                "#part\nname,billing_code,avg_rate\ngamma,3,10.0\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn records_with_too_few_rates_are_rejected() {
+        let input = [
+            line("alpha", "1", &[10.0]),
+            line("beta", "2", &[10.0, 20.0]),
+        ]
+        .concat();
+        let actual = run(&input, &["--min-rate-count", "2"]).unwrap();
+        assert_eq!(actual, "name,billing_code,avg_rate\nbeta,2,15.0\n");
+    }
+
+    #[test]
+    fn running_averages_are_emitted_periodically() {
+        let input = [
+            line("alpha", "1", &[10.0]),
+            line("beta", "2", &[20.0]),
+            line("gamma", "1", &[20.0, 30.0]),
+        ]
+        .concat();
+        let actual = run(&input, &["--group-by", "billing_code", "--emit-every", "2"]).unwrap();
+        let without_timestamps = actual
+            .lines()
+            .map(|line| {
+                let mut fields = line.split(',').collect::<Vec<_>>();
+                fields.remove(1);
+                fields.join(",")
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            without_timestamps,
+            [
+                "snapshot,billing_code,avg_rate,rate_count",
+                "1,1,10.0,1",
+                "1,2,20.0,1",
+                "2,1,20.0,3",
+                "2,2,20.0,1",
+            ]
+        );
+    }
+
+    #[test]
+    fn records_are_written_as_json() {
+        let input = [line("alpha", "1", &[10.0]), line("beta", "2", &[])].concat();
+        let actual = run(&input, &["--format", "json", "--include-null-rate"]).unwrap();
+        assert_eq!(
+            actual,
+            concat!(
+                "[\n",
+                r#"{"name":"alpha","billing_code":"1","avg_rate":10.0},"#,
+                "\n",
+                r#"{"name":"beta","billing_code":"2","avg_rate":null}"#,
+                "\n]\n",
+            )
+        );
+        assert_eq!(run("", &["--format", "json"]).unwrap(), "[]\n");
+    }
+
+    #[test]
+    fn records_are_written_as_ndjson() {
+        let input = [line("alpha", "1", &[10.0]), line("beta", "2", &[40.0])].concat();
+        let actual = run(
+            &input,
+            &[
+                "--format",
+                "ndjson",
+                "--include-rejected",
+                "--annotate-status",
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            actual,
+            concat!(
+                r#"{"name":"alpha","billing_code":"1","avg_rate":10.0,"status":"kept"}"#,
+                "\n",
+                r#"{"name":"beta","billing_code":"2","avg_rate":40.0,"status":"rejected_high"}"#,
+                "\n",
+            )
+        );
+    }
+
+    #[test]
+    fn json_is_pretty_printed_on_request() {
+        let input = line("alpha", "1", &[10.0]);
+        let actual = run(&input, &["--format", "json", "--pretty", "--count-only"]).unwrap();
+        assert_eq!(
+            actual,
+            "[\n{\n  \"billing_code\": \"1\",\n  \"count\": 1\n}\n]\n"
+        );
+        assert!(run(&input, &["--format", "ndjson", "--pretty"]).is_err());
+    }
+
+    #[test]
+    fn missing_fields_are_an_error_by_default() {
+        let input = r#"{"billing_code":"1","negotiated_rates":[]}"#;
+        assert!(run(input, &[]).is_err());
+        let input = r#"{"name":"alpha","negotiated_rates":[]}"#;
+        assert!(run(input, &[]).is_err());
+    }
+
+    #[test]
+    fn missing_fields_are_defaulted_on_request() {
+        let input = [
+            r#"{"billing_code":"1","negotiated_rates":[]}"#,
+            r#"{"name":"beta","negotiated_rates":[]}"#,
+        ]
+        .join("\n");
+        let args = [
+            "--default-missing",
+            "--default-billing-code",
+            "unknown",
+            "--include-null-rate",
+        ];
+        let actual = run(&input, &args).unwrap();
+        assert_eq!(actual, "name,billing_code,avg_rate\n,1,\nbeta,unknown,\n");
+    }
+
+    #[test]
+    fn output_delimiter_is_configurable() {
+        let input = line("alpha", "1", &[10.0]);
+        let expected = "name;billing_code;avg_rate\nalpha;1;10.0\n";
+        assert_eq!(run(&input, &["--delimiter-out", ";"]).unwrap(), expected);
+        assert_eq!(run(&input, &["--delimiter", ";"]).unwrap(), expected);
+        assert!(run(&input, &["--delimiter-out", "é"]).is_err());
+    }
+
+    #[test]
+    fn reading_stops_after_max_records() {
+        let input = [
+            line("alpha", "1", &[40.0]),
+            line("beta", "2", &[10.0]),
+            "not json\n".to_owned(),
+        ]
+        .concat();
+        let options = options(&["--max-records", "2"]);
+        let summary = process(input.as_bytes(), Vec::new(), &options).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                read: 2,
+                written: 1
+            }
+        );
+    }
+
+    #[test]
+    fn failed_lines_are_skipped_up_to_max_errors() {
+        let input = [
+            line("alpha", "1", &[10.0]),
+            "not json\n".to_owned(),
+            line("beta", "2", &[20.0]),
+            "{\n".to_owned(),
+        ]
+        .concat();
+        assert_eq!(
+            run(&input, &["--max-errors", "2", "--quiet"]).unwrap(),
+            "name,billing_code,avg_rate\nalpha,1,10.0\nbeta,2,20.0\n"
+        );
+        let e = run(&input, &["--max-errors", "1", "--quiet"]).unwrap_err();
+        assert_eq!(e.to_string(), "2 lines failed, more than the maximum of 1");
+    }
+
+    #[test]
+    fn csv_output_is_refiltered_with_csv_input() {
+        let input = [
+            line("alpha", "1", &[10.0]),
+            line("beta", "2", &[]),
+            line("gamma", "3", &[30.0]),
+        ]
+        .concat();
+        let csv = run(&input, &[]).unwrap();
+        assert_eq!(
+            run(&csv, &["--input-format", "csv"]).unwrap(),
+            csv,
+            "round trip"
+        );
+        let actual = run(&csv, &["--input-format", "csv", "--max-rate", "20"]).unwrap();
+        assert_eq!(actual, run(&input, &["--max-rate", "20"]).unwrap());
+    }
+
+    #[test]
+    fn output_stops_before_row_exceeding_max_output_bytes() {
+        let input = [
+            line("alpha", "1", &[10.0]),
+            line("beta", "2", &[20.0]),
+            line("gamma", "3", &[30.0]),
+        ]
+        .concat();
+        let expected = "name,billing_code,avg_rate\nalpha,1,10.0\nbeta,2,20.0\n";
+        let max_bytes = expected.len().to_string();
+        let options = options(&["--max-output-bytes", &max_bytes, "--quiet"]);
+        let mut output = Vec::new();
+        let summary = process(input.as_bytes(), &mut output, &options).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+        assert_eq!(summary.written, 2);
+        let max_bytes = (expected.len() + 30).to_string();
+        let actual = run(
+            &input,
+            &["--max-output-bytes", &max_bytes, "--format", "json"],
+        )
+        .unwrap();
+        assert!(actual.len() <= expected.len() + 30, "{actual}");
+        serde_json::from_str::<serde_json::Value>(&actual).unwrap();
+    }
+
+    #[test]
+    fn projected_fields_replace_record_identity() {
+        let input = [
+            r#"{"name":"alpha","billing_code":"1","provider":{"npi":[42]},"#,
+            r#""negotiated_rates":[{"negotiated_prices":[{"negotiated_rate":10.0}]}]}"#,
+            "\n",
+            &line("beta", "2", &[20.0]),
+        ]
+        .concat();
+        let actual = run(
+            &input,
+            &[
+                "--project",
+                "npi=/provider/npi/0",
+                "--project",
+                "code=/billing_code",
+            ],
+        )
+        .unwrap();
+        assert_eq!(actual, "npi,code,avg_rate\n42,1,10.0\n,2,20.0\n");
+        assert!("npi=provider".parse::<Projection>().is_err());
+    }
+
+    #[test]
+    fn per_input_counts_sum_to_totals() {
+        let first = [line("alpha", "1", &[10.0]), "not json\n".to_owned()].concat();
+        let second = [line("beta", "2", &[50.0]), line("gamma", "3", &[20.0])].concat();
+        // The last line of an input need not end with a newline
+        let second = second.trim_end();
+        let options = options(&["--max-errors", "1", "--max-rate", "30", "--quiet"]);
+        let inputs = Inputs::new(vec![
+            ("first".to_owned(), first.as_bytes()),
+            ("second".to_owned(), second.as_bytes()),
+            ("empty".to_owned(), "".as_bytes()),
+        ]);
+        let mut output = Vec::new();
+        let inputs = process_inputs(inputs, &mut output, &options).unwrap();
+        let counts = inputs
+            .iter()
+            .map(|i| (i.input.as_str(), i.read, i.written, i.failed))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            counts,
+            [("first", 1, 1, 1), ("second", 2, 1, 0), ("empty", 0, 0, 0)]
+        );
+        let mut expected = Vec::new();
+        let input = format!("{first}{second}");
+        let summary = process(input.as_bytes(), &mut expected, &options).unwrap();
+        assert_eq!(output, expected);
+        assert_eq!(inputs.iter().map(|i| i.read).sum::<u64>(), summary.read);
+        assert_eq!(
+            inputs.iter().map(|i| i.written).sum::<u64>(),
+            summary.written
+        );
+    }
+
+    #[test]
+    fn parallel_outputs_are_written_in_input_order() {
+        let inputs = [
+            "".to_owned(),
+            [line("alpha", "1", &[10.0]), "not json\n".to_owned()].concat(),
+            line("beta", "2", &[50.0]),
+            [line("gamma", "3", &[20.0]), line("delta", "4", &[30.0])].concat(),
+        ];
+        let names = ["empty", "first", "second", "third"].map(String::from);
+        let options = options(&["--max-errors", "1", "--quiet", "--output-comment", "run"]);
+        let mut output = Vec::new();
+        let open = |i: usize| Ok(inputs[i].as_bytes());
+        let jobs = NonZeroUsize::new(2).unwrap();
+        let summary = process_parallel(&names, open, &mut output, jobs, &options).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "#run\nname,billing_code,avg_rate\nalpha,1,10.0\ngamma,3,20.0\ndelta,4,30.0\n"
+        );
+        assert_eq!(
+            summary,
+            Summary {
+                read: 4,
+                written: 3
+            }
+        );
+        let options = self::options(&[]);
+        let result = process_parallel(&names, open, io::sink(), jobs, &options);
+        let e = result.unwrap_err();
+        assert_eq!(e.to_string(), "failed to process first");
+    }
+
+    #[test]
+    fn failed_parallel_inputs_are_reported_after_the_others_with_max_errors() {
+        let inputs = [
+            ["not json\n"; 2].concat(),
+            line("alpha", "1", &[10.0]),
+            String::new(),
+            line("beta", "2", &[20.0]),
+        ];
+        let names = ["bad", "first", "missing", "second"].map(String::from);
+        let options = options(&["--max-errors", "1", "--quiet"]);
+        let mut output = Vec::new();
+        let open = |i: usize| match i {
+            2 => Err(anyhow::anyhow!("no such file")),
+            i => Ok(inputs[i].as_bytes()),
+        };
+        let jobs = NonZeroUsize::new(1).unwrap();
+        let e = process_parallel(&names, open, &mut output, jobs, &options).unwrap_err();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "name,billing_code,avg_rate\nalpha,1,10.0\nbeta,2,20.0\n"
+        );
+        let e = e.to_string();
+        assert!(
+            e.starts_with("2 of 4 inputs failed: failed to process bad: "),
+            "{e}"
+        );
+        assert!(
+            e.ends_with("; failed to process missing: no such file"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn prefetched_inputs_are_processed_in_parallel() {
+        let inputs = [
+            line("alpha", "1", &[10.0]),
+            "".to_owned(),
+            (0..10_000)
+                .map(|i| line("beta", &i.to_string(), &[20.0]))
+                .collect(),
+            line("gamma", "3", &[40.0]),
+            line("delta", "4", &[5.0]),
+        ];
+        let names = ["first", "empty", "large", "high", "last"].map(String::from);
+        let options = options(&[]);
+        let read = |i: usize| Ok(inputs[i].as_bytes());
+        let open = |_, input| Ok(BufReader::new(input));
+        let jobs = NonZeroUsize::new(2).unwrap();
+        let io_concurrency = NonZeroUsize::new(3).unwrap();
+        let mut output = Vec::new();
+        let summary = process_parallel_prefetched(
+            &names,
+            read,
+            open,
+            &mut output,
+            jobs,
+            io_concurrency,
+            &options,
+        )
+        .unwrap();
+        let mut expected = Vec::new();
+        process(inputs.concat().as_bytes(), &mut expected, &options).unwrap();
+        assert_eq!(output, expected);
+        assert_eq!(
+            summary,
+            Summary {
+                read: 10_003,
+                written: 10_002
+            }
+        );
+        let read = |i: usize| match i {
+            3 => Err(anyhow::anyhow!("unavailable")),
+            i => Ok(inputs[i].as_bytes()),
+        };
+        let result = process_parallel_prefetched(
+            &names,
+            read,
+            open,
+            io::sink(),
+            jobs,
+            io_concurrency,
+            &options,
+        );
+        assert_eq!(result.unwrap_err().to_string(), "failed to process high");
+    }
+
+    #[test]
+    fn median_is_weighted_by_weight_field() {
+        let input = [
+            line("even", "1", &[1.0, 4.0, 2.0, 10.0]),
+            line("odd", "2", &[3.0, 1.0, 2.0]),
+        ]
+        .concat();
+        let actual = run(&input, &["--aggregation", "median"]).unwrap();
+        assert_eq!(
+            actual,
+            "name,billing_code,avg_rate\neven,1,3.0\nodd,2,2.0\n"
+        );
+        let prices = [(10.0, 1.0), (20.0, 4.0), (30.0, 2.0), (40.0, 3.0)]
+            .map(|(rate, claims)| json!({ "negotiated_rate": rate, "claims": claims }));
+        let weighted = json!({
+            "name": "weighted",
+            "billing_code": "3",
+            "negotiated_rates": [{ "negotiated_prices": prices }],
+        });
+        let args = ["--aggregation", "median", "--weight-field", "claims"];
+        // The cumulative weight reaches exactly half of 10 at 20, so the midpoint with 30 is used
+        let actual = run(&format!("{weighted}\n"), &args).unwrap();
+        assert_eq!(actual, "name,billing_code,avg_rate\nweighted,3,25.0\n");
+        assert!(run(&line("alpha", "1", &[10.0]), &args).is_err());
+    }
+
+    #[test]
+    fn mode_is_the_most_common_rate() {
+        let args = ["--aggregation", "mode"];
+        let input = line("alpha", "1", &[10.0, 20.0, 20.0, 30.0]);
+        assert_eq!(
+            run(&input, &args).unwrap(),
+            "name,billing_code,avg_rate\nalpha,1,20.0\n"
+        );
+        let args = [&args[..], &["--mode-precision", "0"]].concat();
+        let input = line("alpha", "1", &[10.2, 9.9, 20.0]);
+        assert_eq!(
+            run(&input, &args).unwrap(),
+            "name,billing_code,avg_rate\nalpha,1,10.0\n"
+        );
+        assert!(run(&input, &["--mode-precision", "0"]).is_err());
+    }
+
+    #[test]
+    fn record_fields_are_read_from_mapped_input_fields() {
+        let input = [
+            r#"{"provider_name":"alpha","name":"ignored","code":"1","negotiated_rates":[]}"#,
+            "\n",
+            r#"{"provider_name":"beta","negotiated_rates":[]}"#,
+            "\n",
+        ]
+        .concat();
+        let args = [
+            "--map",
+            "name=provider_name",
+            "--map",
+            "billing_code=code",
+            "--include-null-rate",
+        ];
+        let options = options(&args);
+        let mut records = records(input.as_bytes(), &options);
+        assert_eq!(records.next().unwrap().unwrap().0.name, "alpha");
+        let e = records.next().unwrap().unwrap_err();
+        assert!(format!("{e:#}").contains("missing field `code`"), "{e:#}");
+        assert!("rate=price".parse::<FieldMapping>().is_err());
+        assert!("name".parse::<FieldMapping>().is_err());
+    }
+
+    #[test]
+    fn geometric_mean_is_less_skewed_than_mean() {
+        let input = line("skewed", "1", &[1.0, 10.0, 100.0, 0.0, -5.0]);
+        let mean = run(&input, &["--max-rate", "1000"]).unwrap();
+        assert_eq!(mean, "name,billing_code,avg_rate\nskewed,1,21.2\n");
+        let args = ["--aggregation", "geomean", "--scale", "1000"];
+        assert_eq!(
+            run(&input, &args).unwrap(),
+            "name,billing_code,avg_rate\nskewed,1,10000\n"
+        );
+        let input = line("negative", "2", &[-1.0]);
+        assert_eq!(
+            run(&input, &["--aggregation", "geomean", "--include-null-rate"]).unwrap(),
+            "name,billing_code,avg_rate\nnegative,2,\n"
+        );
+    }
+
+    #[test]
+    fn mode_ties_are_broken_toward_the_lowest_rate() {
+        let input = line("alpha", "1", &[30.0, 20.0, 30.0, 20.0, 25.0]);
+        assert_eq!(
+            run(&input, &["--aggregation", "mode"]).unwrap(),
+            "name,billing_code,avg_rate\nalpha,1,20.0\n"
+        );
+    }
+
+    #[test]
+    fn records_are_filtered_on_date() {
+        let dated = |name: &str, date: Option<&str>| {
+            let mut record =
+                serde_json::from_str::<serde_json::Value>(&line(name, "1", &[10.0])).unwrap();
+            if let Some(date) = date {
+                record["last_updated_on"] = date.into();
+            }
+            format!("{record}\n")
+        };
+        let input = [
+            dated("a", Some("2024-01-01")),
+            dated("b", Some("2024-01-02")),
+            dated("c", Some("2024-01-31")),
+            dated("d", Some("2024-02-01")),
+            dated("e", None),
+        ]
+        .concat();
+        let names = |args: &[&str]| {
+            let output = run(&input, args).unwrap();
+            output
+                .lines()
+                .skip(1)
+                .map(|line| line.split(',').next().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        let window = ["--after", "2024-01-01", "--before", "2024-02-01"];
+        assert_eq!(names(&window), ["b", "c", "e"]);
+        assert_eq!(
+            names(&[&window[..], &["--require-date"]].concat()),
+            ["b", "c"]
+        );
+        assert_eq!(names(&["--after", "2024-01-31"]), ["d", "e"]);
+        assert_eq!(names(&[]), ["a", "b", "c", "d", "e"]);
+        let input = dated("a", Some("01/02/2024"));
+        let error = run(&input, &["--before", "2024-02-01"]).unwrap_err();
+        assert!(
+            format!("{error:#}")
+                .starts_with("error on line 1: failed to parse record: invalid date `01/02/2024`"),
+            "{error:#}"
+        );
+    }
+
+    #[test]
+    fn shards_partition_billing_codes() {
+        let input = (0..50)
+            .map(|i| line("a", &i.to_string(), &[10.0]))
+            .collect::<String>();
+        let rows = |args: &[&str]| {
+            run(&input, args)
+                .unwrap()
+                .lines()
+                .skip(1)
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        let mut sharded = Vec::new();
+        for index in 1..=4 {
+            let shard = rows(&["--shard", &format!("{index}/4")]);
+            assert!(!shard.is_empty());
+            sharded.extend(shard);
+        }
+        sharded.sort();
+        let mut all = rows(&[]);
+        all.sort();
+        assert_eq!(sharded, all);
+        for shard in ["0/4", "5/4", "1/0", "1"] {
+            assert!(shard.parse::<Shard>().is_err(), "{shard}");
+        }
+    }
+
+    #[test]
+    fn billing_codes_are_normalized_before_matching() {
+        let input = [
+            line("alpha", "42", &[10.0]),
+            line("beta", "a1", &[10.0]),
+            line("gamma", "0", &[10.0]),
+        ]
+        .concat();
+        let filter = [
+            "--billing-code",
+            "0042",
+            "--billing-code",
+            "A1",
+            "--billing-code",
+            "00",
+        ];
+        assert_eq!(run(&input, &filter).unwrap(), "");
+        let args = [&filter[..], &["--code-normalize", "strip-leading-zeros"]].concat();
+        assert_eq!(
+            run(&input, &args).unwrap(),
+            "name,billing_code,avg_rate\nalpha,42,10.0\ngamma,0,10.0\n"
+        );
+        let args = [&filter[..], &["--code-normalize", "upper"]].concat();
+        assert_eq!(
+            run(&input, &args).unwrap(),
+            "name,billing_code,avg_rate\nbeta,a1,10.0\n"
+        );
+    }
+
+    #[test]
+    fn distinct_rates_are_estimated_within_error_bound() {
+        let mut distinct_rates = HyperLogLog::new();
+        assert_eq!(distinct_rates.estimate(), 0.0);
+        for i in 0..100_000 {
+            distinct_rates.add(f64::from(i) / 100.0);
+            distinct_rates.add(f64::from(i % 10) / 100.0);
+        }
+        let error = (distinct_rates.estimate() - 100_000.0).abs() / 100_000.0;
+        assert!(error < 0.03, "relative error {error}");
+        let mut zeros = HyperLogLog::new();
+        zeros.add(0.0);
+        zeros.add(-0.0);
+        assert_eq!(zeros.estimate().round(), 1.0);
+    }
+
+    #[test]
+    fn errors_are_categorized() {
+        let input = [
+            "{\n",
+            r#"{"name":"alpha","negotiated_rates":[]}"#,
+            "\n",
+            r#"{"name":1,"billing_code":"1","negotiated_rates":[]}"#,
+            "\n",
+            r#"{"name":"a","name":"b","billing_code":"1","negotiated_rates":[]}"#,
+            "\n",
+            &line("beta", "2", &[10.0]).replace("10.0", "1e999"),
+            &line("gamma", "3", &[10.0]),
+        ]
+        .concat();
+        let mut categories = BTreeMap::new();
+        for e in records(input.as_bytes(), &options(&[])).filter_map(Result::err) {
+            *categories.entry(ErrorCategory::of(&e)).or_default() += 1;
+        }
+        assert_eq!(
+            error_report(&categories),
+            "errors by category: lines\n\
+            syntax: 1\n\
+            number out of range: 1\n\
+            missing field: 1\n\
+            duplicate field: 1\n\
+            type mismatch: 1\n"
+        );
+    }
+
+    #[test]
+    fn records_taking_too_long_to_aggregate_fail() {
+        let prices = vec![10.0; 10_000];
+        let input = [line("huge", "1", &prices), line("small", "2", &[20.0])].concat();
+        assert_eq!(
+            run(&input, &["--per-record-timeout", "1m"]).unwrap(),
+            "name,billing_code,avg_rate\nhuge,1,10.0\nsmall,2,20.0\n"
+        );
+        let timeout = ["--per-record-timeout", "0.000001"];
+        let error = run(&input, &timeout).unwrap_err();
+        assert!(
+            format!("{error:#}").starts_with(
+                "error on line 1: failed to parse record: record took longer than 1µs to aggregate"
+            ),
+            "{error:#}"
+        );
+        let args = [&timeout[..], &["--max-errors", "1", "--quiet"]].concat();
+        assert_eq!(
+            run(&input, &args).unwrap(),
+            "name,billing_code,avg_rate\nsmall,2,20.0\n"
+        );
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert!(parse_duration("1d").is_err());
+    }
+
+    #[test]
+    fn duplicate_codes_are_errors_on_request() {
+        let input = [
+            line("a", "1", &[10.0]),
+            line("b", "2", &[10.0]),
+            line("c", "1", &[20.0]),
+            line("d", "3", &[10.0]),
+        ]
+        .concat();
+        assert!(run(&input, &[]).is_ok());
+        let error = run(&input, &["--error-on-duplicate-code"]).unwrap_err();
+        assert_eq!(
+            format!("{error:#}"),
+            "error on line 3: duplicate billing code 1, first seen on line 1"
+        );
+        let args = ["--error-on-duplicate-code", "--max-errors", "1", "--quiet"];
+        assert_eq!(
+            run(&input, &args).unwrap(),
+            "name,billing_code,avg_rate\na,1,10.0\nb,2,10.0\nd,3,10.0\n"
+        );
+    }
+
+    #[test]
+    fn empty_input_is_an_error_on_request() {
+        let args = ["--error-on-empty-input"];
+        assert_eq!(run("", &[]).unwrap(), "");
+        let e = run("", &args).unwrap_err();
+        assert_eq!(e.to_string(), "no records were read from the input");
+        let filtered = line("alpha", "1", &[50.0]);
+        assert_eq!(run(&filtered, &args).unwrap(), "");
+    }
+
+    #[test]
+    fn columns_are_selected_and_ordered() {
+        let input = line("alpha", "1", &[10.0]);
+        assert_eq!(
+            run(&input, &["--columns", "avg_rate,name"]).unwrap(),
+            "avg_rate,name\n10.0,alpha\n"
+        );
+        assert_eq!(
+            run(
+                &input,
+                &["--emit-every", "1", "--columns", "rate_count,avg_rate"]
+            )
+            .unwrap(),
+            "rate_count,avg_rate\n1,10.0\n"
+        );
+        assert!(run(&input, &["--columns", "name,rate_count"]).is_err());
+        assert!(run(&input, &["--columns", "name,name"]).is_err());
+    }
+
+    #[test]
+    fn nested_stages_are_timed_separately() {
+        let profiling = Profiling::start();
+        timed(Stage::Read, || {
+            thread::sleep(Duration::from_millis(20));
+            timed(Stage::Decompress, || {
+                thread::sleep(Duration::from_millis(30))
+            });
+        });
+        let stages = profiling.stages();
+        assert!(stages[&Stage::Read] >= Duration::from_millis(20));
+        assert!(stages[&Stage::Read] < Duration::from_millis(50));
+        assert!(stages[&Stage::Decompress] >= Duration::from_millis(30));
+        let stages = BTreeMap::from([
+            (Stage::Read, Duration::from_millis(250)),
+            (Stage::Parse, Duration::from_millis(750)),
+        ]);
+        assert_eq!(
+            profile_report(&stages),
+            "time by stage:\nread: 0.250s (25.0%)\nparse: 0.750s (75.0%)\n"
+        );
+    }
+
+    #[test]
+    fn arrow_stream_is_written_in_batches() {
+        let input = [
+            line("alpha", "1", &[10.0]),
+            line("beta", "2", &[]),
+            line("gamma", "3", &[30.0]),
+        ]
+        .concat();
+        let options = options(&[
+            "--format",
+            "arrow",
+            "--arrow-batch-size",
+            "2",
+            "--include-null-rate",
+        ]);
+        let mut output = Vec::new();
+        process(input.as_bytes(), &mut output, &options).unwrap();
+        let batches = StreamReader::try_new(output.as_slice(), None)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            batches.iter().map(|b| b.num_rows()).collect::<Vec<_>>(),
+            [2, 1]
+        );
+        let rates = batches[0]
+            .column_by_name("avg_rate")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        assert_eq!(rates.iter().collect::<Vec<_>>(), [Some(10.0), None]);
+        assert!(run(&input, &["--format", "arrow", "--explode-prices"]).is_err());
+    }
+
+    #[test]
+    fn rows_are_sampled_reproducibly_with_seed() {
+        let input = (0..1000)
+            .map(|i| line("alpha", &i.to_string(), &[10.0]))
+            .collect::<String>();
+        let args = ["--sample-rate", "0.1", "--seed", "7"];
+        let sampled = run(&input, &args).unwrap();
+        assert_eq!(sampled, run(&input, &args).unwrap());
+        let rows = sampled.lines().count() - 1;
+        assert!((50..150).contains(&rows), "{rows} rows sampled");
+        assert_eq!(run(&input, &["--sample-rate", "0"]).unwrap(), "");
+        assert!(run(&input, &["--sample-rate", "1.5"]).is_err());
+    }
+
+    #[test]
+    fn rows_are_grouped_by_billing_code() {
+        let input = [
+            line("a", "2", &[10.0]),
+            line("b", "1", &[20.0]),
+            line("c", "2", &[]),
+            line("d", "2", &[50.0]),
+        ]
+        .concat();
+        let args = ["--format", "grouped-json", "--include-null-rate"];
+        let actual = serde_json::from_str::<serde_json::Value>(&run(&input, &args).unwrap());
+        assert_eq!(
+            actual.unwrap(),
+            json!({
+                "2": [{"name": "a", "avg_rate": 10.0}, {"name": "c", "avg_rate": null}],
+                "1": [{"name": "b", "avg_rate": 20.0}],
+            })
+        );
+        assert_eq!(run("", &args).unwrap(), "{}\n");
+    }
+
+    #[test]
+    fn table_columns_are_aligned() {
+        let input = [
+            line("a", "12345", &[5.0]),
+            line("longer name", "1", &[12.5]),
+            line("b", "2", &[]),
+        ]
+        .concat();
+        let args = ["--format", "table", "--include-null-rate"];
+        assert_eq!(
+            run(&input, &args).unwrap(),
+            "name         billing_code  avg_rate\n\
+             -----------  ------------  --------\n\
+             a            12345              5.0\n\
+             longer name  1                 12.5\n\
+             b            2\n"
+        );
+        let args = [&args[..], &["--table-max-rows", "1"]].concat();
+        assert_eq!(
+            run(&input, &args).unwrap(),
+            "name  billing_code  avg_rate\n----  ------------  --------\na     12345              5.0\n"
+        );
+    }
+
+    #[test]
+    fn input_objects_are_written_with_average_rate() {
+        let record = json!({
+            "name": "alpha",
+            "billing_code": "1",
+            "description": null,
+            "negotiated_rates": [
+                {
+                    "provider_references": [1, 2],
+                    "negotiated_prices": [{"negotiated_rate": 10.0}, {"negotiated_rate": 20.0}],
+                },
+            ],
+        });
+        let empty = json!({"name": "beta", "billing_code": "2", "negotiated_rates": []});
+        let input = format!("{record}\n{empty}\n{}", line("gamma", "3", &[50.0]));
+        let args = ["--format", "enriched-json", "--include-null-rate"];
+        let mut expected = record.clone();
+        expected["avg_rate"] = json!(15.0);
+        let mut expected_empty = empty.clone();
+        expected_empty["avg_rate"] = json!(null);
+        assert_eq!(
+            run(&input, &args).unwrap(),
+            format!("{expected}\n{expected_empty}\n")
+        );
+        let args = [&args[..], &["--json-null-mode", "omit"]].concat();
+        assert_eq!(
+            run(&input, &args).unwrap(),
+            format!("{expected}\n{empty}\n")
+        );
+    }
+
+    #[test]
+    fn null_fields_are_omitted_from_json_on_request() {
+        let input = line("alpha", "1", &[]);
+        let args = ["--format", "ndjson", "--include-null-rate"];
+        assert_eq!(
+            run(&input, &args).unwrap(),
+            "{\"name\":\"alpha\",\"billing_code\":\"1\",\"avg_rate\":null}\n"
+        );
+        let args = [&args[..], &["--json-null-mode", "omit"]].concat();
+        assert_eq!(
+            run(&input, &args).unwrap(),
+            "{\"name\":\"alpha\",\"billing_code\":\"1\"}\n"
+        );
+        assert!(run(&input, &["--json-null-mode", "omit"]).is_err());
+    }
+
+    #[test]
+    fn concatenated_objects_are_parsed_with_multi_object_lines() {
+        let first = line("alpha", "1", &[10.0]);
+        let second = line("beta", "2", &[20.0]);
+        let input = format!("{}{}", first.trim_end(), second);
+        assert_eq!(
+            run(&input, &["--multi-object-lines"]).unwrap(),
+            "name,billing_code,avg_rate\nalpha,1,10.0\nbeta,2,20.0\n"
+        );
+        let e = run(&input, &[]).unwrap_err();
+        assert_eq!(e.to_string(), "error on line 1");
+    }
+
+    #[test]
+    fn schema_matches_written_header() {
+        let input = line("alpha", "1", &[10.0]);
+        let flag_sets: [&[&str]; 5] = [
+            &[],
+            &["--explode-prices"],
+            &["--include-rejected", "--annotate-status"],
+            &["--count-only", "--key", "billing_code,billing_code_type"],
+            &["--emit-every", "1"],
+        ];
+        for args in flag_sets {
+            let mut schema = Vec::new();
+            write_schema(&mut schema, &options(args)).unwrap();
+            let output = run(&input, args).unwrap();
+            let header = output.lines().next().unwrap();
+            assert_eq!(String::from_utf8(schema).unwrap(), format!("{header}\n"));
+        }
+    }
+
+    #[test]
+    fn schema_is_a_json_array_with_json_output() {
+        let mut schema = Vec::new();
+        write_schema(&mut schema, &options(&["--format", "ndjson"])).unwrap();
+        assert_eq!(schema, b"[\"name\",\"billing_code\",\"avg_rate\"]\n");
+    }
+
+    #[test]
+    fn nonfinite_rates_are_ignored_or_propagated_in_extrema() {
+        let accumulate = |policy| {
+            let options = ProcessOptions {
+                nonfinite_minmax: policy,
+                ..Default::default()
+            };
+            [10.0, f64::NAN, 40.0, f64::INFINITY]
+                .into_iter()
+                .fold(AccumulatedRate::new(&options), |acc, negotiated_rate| {
+                    acc + NegotiatedPrice { negotiated_rate }
+                })
+        };
+        let ignored = accumulate(NonFiniteMinMax::Ignore);
+        assert_eq!(ignored.min(), Some(10.0));
+        assert_eq!(ignored.max(), Some(40.0));
+        let propagated = accumulate(NonFiniteMinMax::Propagate);
+        assert!(propagated.min().is_some_and(f64::is_nan));
+        assert!(propagated.max().is_some_and(f64::is_nan));
+    }
+
+    #[test]
+    fn progress_is_reported_as_text_or_json() {
+        let summary = Summary {
+            read: 12,
+            written: 3,
+        };
+        let elapsed = Duration::from_millis(1500);
+        assert_eq!(
+            progress_line(ProgressFormat::Text, &summary, elapsed),
+            "read 12 records, wrote 3 in 1.5s"
+        );
+        assert_eq!(
+            progress_line(ProgressFormat::Json, &summary, elapsed),
+            r#"{"read":12,"written":3,"elapsed_ms":1500}"#
+        );
+    }
+
+    #[test]
+    fn rate_thresholds_are_compared_with_epsilon() {
+        let input = line("a", "1", &[0.1, 0.2]);
+        assert_eq!(run(&input, &["--max-rate", "0.15"]).unwrap(), "");
+        let args = ["--max-rate", "0.15", "--rate-epsilon", "1e-9"];
+        assert_eq!(
+            run(&input, &args).unwrap(),
+            "name,billing_code,avg_rate\na,1,0.15000000000000002\n"
+        );
+        let args = ["--min-rate", "0.15000000001", "--rate-epsilon", "1e-9"];
+        assert!(run(&input, &args).unwrap().contains("a,1,"));
+    }
+
+    #[test]
+    fn config_shows_resolved_options() {
+        let mut output = Vec::new();
+        let options = options(&[
+            "--max-rate",
+            "50",
+            "--format",
+            "ndjson",
+            "--key",
+            "billing_code",
+        ]);
+        write_config(&mut output, &options).unwrap();
+        let config = serde_json::from_slice::<serde_json::Value>(&output).unwrap();
+        assert_eq!(config["max_rate"], json!(50.0));
+        assert_eq!(config["format"], json!("ndjson"));
+        assert_eq!(config["key"], json!(["billing_code"]));
+        assert_eq!(config["rounding"], json!("half-even"));
+        assert_eq!(config["min_rate"], json!(null));
+    }
+
+    #[test]
+    fn csv_outputs_are_combined_under_one_header() {
+        let inputs = [
+            ("a.csv", "name,billing_code,avg_rate\na,1,10.0\nb,2,20.0\n"),
+            ("b.csv", ""),
+            (
+                "c.csv",
+                "#comment\nname,billing_code,avg_rate\n\"c, d\",3,30.0\n",
+            ),
+            ("d.csv", "name,billing_code,avg_rate\ne,4,\n"),
+        ];
+        let inputs = inputs
+            .iter()
+            .map(|&(name, contents)| (name.to_owned(), contents.as_bytes()))
+            .collect::<Vec<_>>();
+        let mut output = Vec::new();
+        let rows = combine_csv(inputs, &mut output, &ProcessOptions::default()).unwrap();
+        assert_eq!(rows, 4);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "name,billing_code,avg_rate\na,1,10.0\nb,2,20.0\n\"c, d\",3,30.0\ne,4,\n"
+        );
+        let inputs = vec![
+            (
+                "a.csv".to_owned(),
+                "name,billing_code,avg_rate\n".as_bytes(),
+            ),
+            ("b.csv".to_owned(), "name,billing_code\n".as_bytes()),
+        ];
+        let error = combine_csv(inputs, io::sink(), &ProcessOptions::default()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "header of b.csv (name,billing_code) differs from that of a.csv \
+             (name,billing_code,avg_rate)"
+        );
+    }
+
+    #[test]
+    fn probe_reports_fields_of_first_lines() {
+        let input = [
+            json!({
+                "name": "a",
+                "billing_code": "1",
+                "billing_code_type": "CPT",
+                "negotiated_rates": [
+                    {"negotiated_prices": [{"negotiated_rate": 1.0, "currency": "USD"}]},
+                ],
+            })
+            .to_string(),
+            json!({"billing_code": "2", "negotiated_rates": []}).to_string(),
+            line("c", "3", &[1.0]),
+        ]
+        .join("\n");
+        let report = probe(input.as_bytes(), 2, &ProcessOptions::default()).unwrap();
+        let counts = |counts: &[(&str, u64)]| {
+            counts
+                .iter()
+                .map(|&(field, count)| (field.to_owned(), count))
+                .collect::<BTreeMap<_, _>>()
+        };
+        assert_eq!(report.lines, 2);
+        assert_eq!(report.records, 1);
+        assert_eq!(
+            report.fields,
+            counts(&[
+                ("billing_code", 2),
+                ("billing_code_type", 1),
+                ("name", 1),
+                ("negotiated_rates", 2),
+            ])
+        );
+        assert_eq!(
+            report.price_fields,
+            counts(&[("currency", 1), ("negotiated_rate", 1)])
+        );
+        assert_eq!(
+            report.errors.keys().collect::<Vec<_>>(),
+            [&2],
+            "{:?}",
+            report.errors
+        );
+    }
+
+    #[test]
+    fn only_outliers_are_written_on_request() {
+        let input = [
+            line("a", "1", &[10.0]),
+            line("b", "2", &[11.0]),
+            line("c", "3", &[9.0]),
+            line("d", "4", &[10.0]),
+            line("e", "5", &[30.0]),
+            line("f", "6", &[]),
+            line("g", "7", &[100.0]),
+        ]
+        .concat();
+        // Mean 14 and standard deviation ~8.97
+        let args = [
+            "--outliers",
+            "1.5",
+            "--max-rate",
+            "50",
+            "--include-null-rate",
+        ];
+        assert_eq!(
+            run(&input, &args).unwrap(),
+            "name,billing_code,avg_rate\ne,5,30.0\n"
+        );
+    }
+
+    #[test]
+    fn rates_are_packed_in_a_column_on_request() {
+        let input = line("a", "1", &[10.0, 20.5, 24.0]);
+        let output = run(&input, &["--with-rates"]).unwrap();
+        assert_eq!(
+            output,
+            "name,billing_code,avg_rate,rates\na,1,18.166666666666668,10.0;20.5;24.0\n"
+        );
+        let row = output
+            .lines()
+            .nth(1)
+            .unwrap()
+            .split(',')
+            .collect::<Vec<_>>();
+        let rates = row[3]
+            .split(';')
+            .map(|rate| rate.parse::<f64>().unwrap())
+            .collect::<Vec<_>>();
+        let mean = rates.iter().sum::<f64>() / rates.len() as f64;
+        assert_eq!(mean, row[2].parse::<f64>().unwrap());
+    }
+
+    #[test]
+    fn rate_frequencies_are_packed_in_a_column_on_request() {
+        let input = [
+            line("a", "1", &[20.0, 10.0, 20.0, -0.0, 0.0, 20.0]),
+            line("b", "2", &[]),
+        ]
+        .concat();
+        let output = run(&input, &["--rate-frequencies", "--include-null-rate"]).unwrap();
+        assert_eq!(
+            output,
+            "name,billing_code,avg_rate,rate_frequencies\n\
+             a,1,11.666666666666666,0.0:2;10.0:1;20.0:3\n\
+             b,2,,\n"
+        );
+        let args = [
+            "--rate-frequencies",
+            "--decimal-comma",
+            "--delimiter-out",
+            "\t",
+        ];
+        let output = run(&line("a", "1", &[10.5, 10.5, 2.0]), &args).unwrap();
+        assert_eq!(
+            output,
+            "name\tbilling_code\tavg_rate\trate_frequencies\na\t1\t7,666666666666667\t2,0:1;10,5:2\n"
+        );
+    }
+
+    #[test]
+    fn output_is_verified_to_round_trip() {
+        let input = [
+            line("a, \"quoted\"", "1", &[10.5]),
+            line("multi\nline;", "2", &[1e-7]),
+            line("#hash", "3", &[]),
+        ]
+        .concat();
+        let path = std::env::temp_dir().join(format!("etl-roundtrip-{}.csv", std::process::id()));
+        let options = options(&["--include-null-rate", "--output-comment", "note"]);
+        let reopen = || Ok(BufReader::new(fs::File::open(&path)?));
+        let summary = process_verified(
+            input.as_bytes(),
+            fs::File::create(&path).unwrap(),
+            reopen,
+            &options,
+        );
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(summary.unwrap().written, 3);
+        assert_eq!(
+            written,
+            "#note\nname,billing_code,avg_rate\n\"a, \"\"quoted\"\"\",1,10.5\n\
+             \"multi\nline;\",2,1e-7\n#hash,3,\n"
+        );
+        let tampered = || Ok(written.replace("10.5", "10.50001").as_bytes().to_vec());
+        let error = process_verified(
+            input.as_bytes(),
+            io::sink(),
+            || tampered().map(io::Cursor::new),
+            &options,
+        )
+        .unwrap_err();
+        assert!(
+            format!("{error:#}").starts_with("output does not round-trip: line 3 reads back as"),
+            "{error:#}"
+        );
+    }
+
+    #[test]
+    fn rolling_averages_are_computed_over_window() {
+        let input = [
+            line("a", "1", &[10.0]),
+            line("b", "2", &[20.0]),
+            line("c", "3", &[40.0]),
+            line("d", "4", &[]),
+            line("e", "5", &[36.0]),
+            line("f", "6", &[0.0]),
+        ]
+        .concat();
+        let args = [
+            "--rolling-window",
+            "2",
+            "--include-null-rate",
+            "--max-rate",
+            "39",
+        ];
+        assert_eq!(
+            run(&input, &args).unwrap(),
+            "name,billing_code,avg_rate,rolling_avg_rate\n\
+             a,1,10.0,10.0\n\
+             b,2,20.0,15.0\n\
+             d,4,,15.0\n\
+             e,5,36.0,28.0\n\
+             f,6,0.0,18.0\n"
+        );
+    }
+
+    #[test]
+    fn summary_row_is_appended_on_request() {
+        let input = [
+            line("a", "1", &[10.0]),
+            line("b", "2", &[20.0, 30.0]),
+            line("c", "3", &[50.0]),
+            line("d", "4", &[]),
+        ]
+        .concat();
+        let args = ["--append-summary-row", "--include-null-rate"];
+        assert_eq!(
+            run(&input, &args).unwrap(),
+            "name,billing_code,avg_rate\na,1,10.0\nb,2,25.0\nd,4,\nTOTAL,3,17.5\n"
+        );
+        assert!(run(&input, &["--append-summary-row", "--format", "json"]).is_err());
+    }
+
+    #[test]
+    fn csv_quoting_is_configurable() {
+        let input = line("a, b", "1", &[10.0]);
+        let expected = [
+            ("necessary", "name,billing_code,avg_rate\n\"a, b\",1,10.0\n"),
+            (
+                "always",
+                "\"name\",\"billing_code\",\"avg_rate\"\n\"a, b\",\"1\",\"10.0\"\n",
+            ),
+            (
+                "non-numeric",
+                "\"name\",\"billing_code\",\"avg_rate\"\n\"a, b\",1,10.0\n",
+            ),
+            ("never", "name,billing_code,avg_rate\na, b,1,10.0\n"),
+        ];
+        for (quote, expected) in expected {
+            assert_eq!(run(&input, &["--csv-quote", quote]).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn decimal_comma_is_written_on_request() {
+        let input = [line("alpha", "1", &[10.5]), line("beta", "2", &[])].concat();
+        let args = [
+            "--decimal-comma",
+            "--delimiter-out",
+            ";",
+            "--include-null-rate",
+            "--include-rejected",
+            "--annotate-status",
+        ];
+        let actual = run(&input, &args).unwrap();
+        assert_eq!(
+            actual,
+            "name;billing_code;avg_rate;status\nalpha;1;10,5;kept\nbeta;2;;kept\n"
+        );
+        let actual = run(
+            &input,
+            &["--decimal-comma", "--delimiter", ";", "--count-only"],
+        );
+        assert_eq!(actual.unwrap(), "billing_code;count\n1;1\n");
+        let input = line("alpha", "1", &[10.5, 11.0]);
+        let actual = run(
+            &input,
+            &["--decimal-comma", "--delimiter", ";", "--with-rates"],
+        );
+        assert_eq!(
+            actual.unwrap(),
+            "name;billing_code;avg_rate;rates\nalpha;1;10,75;\"10,5;11,0\"\n"
+        );
+    }
+
+    #[test]
+    fn scaled_rates_are_rounded_at_half_boundary() {
+        let input = [line("alpha", "1", &[0.125]), line("beta", "2", &[0.375])].concat();
+        let actual = run(&input, &["--scale", "100"]).unwrap();
+        assert_eq!(
+            actual,
+            "name,billing_code,avg_rate\nalpha,1,12\nbeta,2,38\n"
+        );
+        let actual = run(&input, &["--scale", "100", "--rounding", "half-up"]).unwrap();
+        assert_eq!(
+            actual,
+            "name,billing_code,avg_rate\nalpha,1,13\nbeta,2,38\n"
+        );
+    }
+
+    #[test]
+    fn rounding_mode_applies_at_half_boundary() {
+        let input = [line("alpha", "1", &[12.5]), line("beta", "2", &[-12.5])].concat();
+        for (mode, alpha, beta) in [
+            ("half-even", 12, -12),
+            ("half-up", 13, -13),
+            ("floor", 12, -13),
+            ("ceil", 13, -12),
+            ("trunc", 12, -12),
+        ] {
+            let actual = run(&input, &["--scale", "1", "--round-mode", mode]).unwrap();
+            assert_eq!(
+                actual,
+                format!("name,billing_code,avg_rate\nalpha,1,{alpha}\nbeta,2,{beta}\n"),
+                "{mode}"
+            );
+        }
+        // Rounding down counts 2.54 and 2.55 as 2.5, rounding halves up counts 2.55 as 2.6
+        let input = line("alpha", "1", &[2.54, 2.55, 2.6]);
+        let args = ["--aggregation", "mode", "--mode-precision", "1"];
+        let actual = run(&input, &[&args[..], &["--round-mode", "floor"]].concat()).unwrap();
+        assert_eq!(actual, "name,billing_code,avg_rate\nalpha,1,2.5\n");
+        let actual = run(&input, &[&args[..], &["--round-mode", "half-up"]].concat()).unwrap();
+        assert_eq!(actual, "name,billing_code,avg_rate\nalpha,1,2.6\n");
+    }
+
+    #[test]
+    fn decimal_comma_conflicts_with_comma_delimiter() {
+        let input = line("alpha", "1", &[10.5]);
+        assert!(run(&input, &["--decimal-comma"]).is_err());
+        assert!(run(&input, &["--decimal-comma", "--delimiter-out", ","]).is_err());
+        let mut schema = Vec::new();
+        assert!(write_schema(&mut schema, &options(&["--decimal-comma"])).is_err());
+    }
+
+    #[test]
+    fn average_only_includes_first_price_of_each_group() {
+        let input = json!({
+            "name": "alpha",
+            "billing_code": "1",
+            "negotiated_rates": [
+                {
+                    "negotiated_prices": [
+                        {
+                            "negotiated_rate": 10,
+                        },
+                        {
+                            "negotiated_rate": 60,
+                        },
+                    ],
+                },
+                {
+                    "negotiated_prices": [
+                        {
+                            "negotiated_rate": 30,
+                        },
+                        {
+                            "negotiated_rate": 90,
+                        },
+                    ],
+                },
+            ],
+        });
+
+        let (record, rate) = RecordSeed {
+            options: &options(&["--first-price-only"]),
+        }
+        .deserialize(input)
+        .unwrap();
+        assert_eq!(record.avg_rate, Some(20.0));
+        assert_eq!(rate.count(), 2);
+    }
+
+    #[test]
+    fn variance_is_accumulated_across_groups() {
+        let input = json!({
+            "name": "alpha",
+            "billing_code": "1",
+            "negotiated_rates": [
+                {
+                    "negotiated_prices": [
+                        {
+                            "negotiated_rate": 2,
+                        },
+                        {
+                            "negotiated_rate": 4,
+                        },
+                    ],
+                },
+                {
+                    "negotiated_prices": [],
+                },
+                {
+                    "negotiated_prices": [
+                        {
+                            "negotiated_rate": 9,
+                        },
+                    ],
+                },
+            ],
+        });
+        let rate = RecordSeed {
+            options: &ProcessOptions::default(),
+        }
+        .deserialize(input)
+        .unwrap()
+        .1;
+        assert_eq!(rate.variance(), Some(13.0));
+        assert_eq!(rate.coefficient_of_variation(), Some(13f64.sqrt() / 5.0));
+    }
+
+    #[test]
+    fn records_with_too_variable_rates_are_rejected() {
+        let input = [
+            line("alpha", "1", &[10.0, 12.0]),
+            line("beta", "2", &[1.0, 29.0]),
+            line("gamma", "3", &[25.0]),
+        ]
+        .concat();
+        let args = [
+            "--max-coefficient-of-variation",
+            "0.5",
+            "--include-rejected",
+            "--annotate-status",
+        ];
+        let actual = run(&input, &args).unwrap();
+        assert_eq!(
+            actual,
+            "name,billing_code,avg_rate,status\n\
+             alpha,1,11.0,kept\n\
+             beta,2,15.0,rejected_variation\n\
+             gamma,3,25.0,kept\n"
+        );
+    }
+
+    #[test]
+    fn missing_expected_codes_are_reported() {
+        let path = std::env::temp_dir().join(format!("etl-expected-{}.txt", std::process::id()));
+        fs::write(&path, "1\n 3\n\n4\n1\n").unwrap();
+        let options = options(&["--expected-codes", path.to_str().unwrap()]);
+        let expected = expected_codes(&options);
+        fs::remove_file(&path).unwrap();
+        let expected = expected.unwrap().unwrap();
+        assert_eq!(expected, ["1", "3", "4"]);
+        let input = [
+            line("a", "1", &[10.0]),
+            line("b", "2", &[10.0]),
+            line("c", "3", &[40.0]),
+        ]
+        .concat();
+        let mut output = RowWriter::new(Vec::new(), None, Resume::Start, &options).unwrap();
+        for r in records(input.as_bytes(), &options) {
+            let (r, rate) = r.unwrap();
+            let disposition = options.disposition(r.avg_rate, &rate);
+            output.write(&r, &r, disposition).unwrap();
+        }
+        assert_eq!(
+            coverage_report(&expected, output.present.as_ref().unwrap()),
+            "expected billing codes: 1 of 3 present\nmissing: 3\nmissing: 4\n"
+        );
+        let args = ["etl", "--expected-codes", "codes.txt", "--quiet"];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn baseline_is_read_with_output_options() {
+        let path = std::env::temp_dir().join(format!("etl-rebaseline-{}.csv", std::process::id()));
+        let previous = [line("a", "1", &[10.25]), line("b", "2", &[20.0])].concat();
+        let current = [line("a", "1", &[10.25]), line("b", "2", &[25.5])].concat();
+        let baseline = ["--baseline", path.to_str().unwrap()];
+        let runs = [
+            (
+                &["--delimiter-out", ";", "--decimal-comma"][..],
+                "b;2;25,5;20,0;5,5\n",
+            ),
+            (
+                &["--delimiter-out", ";", "--scale", "100"][..],
+                "b;2;2550;2000;550\n",
+            ),
+        ];
+        let actual = runs
+            .iter()
+            .map(|(args, _)| {
+                fs::write(&path, run(&previous, args)?)?;
+                run(&current, &[&baseline[..], args].concat())
+            })
+            .collect::<Vec<_>>();
+        fs::remove_file(&path).unwrap();
+        for (actual, (_, row)) in actual.into_iter().zip(runs) {
+            assert_eq!(
+                actual.unwrap(),
+                format!("name;billing_code;avg_rate;prev_rate;delta\n{row}")
+            );
+        }
+    }
+
+    #[test]
+    fn scaled_output_is_unchanged_from_itself_as_baseline() {
+        let path =
+            std::env::temp_dir().join(format!("etl-selfbaseline-{}.csv", std::process::id()));
+        let input = [line("a", "1", &[10.0]), line("b", "2", &[12.335, 12.34])].concat();
+        let scaled = ["--scale", "100"];
+        let previous = run(&input, &scaled).unwrap();
+        fs::write(&path, &previous).unwrap();
+        let baseline = ["--baseline", path.to_str().unwrap()];
+        let actual = run(&input, &[&scaled[..], &baseline].concat());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(previous, "name,billing_code,avg_rate\na,1,1000\nb,2,1234\n");
+        assert_eq!(actual.unwrap(), "");
+    }
+
+    #[test]
+    fn only_changes_from_baseline_are_written() {
+        let path = std::env::temp_dir().join(format!("etl-baseline-{}.csv", std::process::id()));
+        fs::write(
+            &path,
+            "#previous run\nname,billing_code,avg_rate\na,1,10.0\nb,2,20.0\nc,4,\n",
+        )
+        .unwrap();
+        let input = [
+            line("unchanged", "1", &[10.5]),
+            line("changed", "2", &[25.0]),
+            line("new", "3", &[5.0]),
+            line("priced", "4", &[5.0]),
+        ]
+        .concat();
+        let args = ["--baseline", path.to_str().unwrap(), "--min-delta", "1"];
+        let actual = run(&input, &args);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            actual.unwrap(),
+            "name,billing_code,avg_rate,prev_rate,delta\n\
+             changed,2,25.0,20.0,5.0\n\
+             new,3,5.0,,\n\
+             priced,4,5.0,,\n"
+        );
+    }
+
+    #[test]
+    fn blank_and_comment_lines_are_skipped_and_counted() {
+        let input = [
+            "# header comment\n".to_owned(),
+            line("alpha", "1", &[10.0]),
+            "\n".to_owned(),
+            "   \n".to_owned(),
+            "  # indented comment\n".to_owned(),
+            line("beta", "2", &[20.0]),
+            "not json\n".to_owned(),
+            "\n".to_owned(),
+        ]
+        .concat();
+        let path = std::env::temp_dir().join(format!("etl-skipped-{}.json", std::process::id()));
+        let result_file = path.to_str().unwrap();
+        let args = [
+            "--allow-comments",
+            "--result-file",
+            result_file,
+            "--max-errors",
+            "1",
+            "--quiet",
+        ];
+        let mut output = Vec::new();
+        process(input.as_bytes(), &mut output, &options(&args)).unwrap();
+        let result = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "name,billing_code,avg_rate\nalpha,1,10.0\nbeta,2,20.0\n"
+        );
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&result).unwrap(),
+            json!({
+                "read": 2,
+                "passed": 2,
+                "rejected": 0,
+                "errors": 1,
+                "blank_lines": 3,
+                "comment_lines": 2,
+            })
+        );
+        let error = run(&input, &[]).unwrap_err();
+        assert!(
+            format!("{error:#}").starts_with("error on line 1:"),
+            "{error:#}"
+        );
+    }
+
+    #[test]
+    fn result_is_written_on_success_and_failure() {
+        let input = [
+            line("alpha", "1", &[10.0]),
+            line("beta", "1", &[40.0]),
+            "not json\n".to_owned(),
+            line("gamma", "2", &[20.0]),
+        ]
+        .concat();
+        let path = std::env::temp_dir().join(format!("etl-result-{}.json", std::process::id()));
+        let result_file = path.to_str().unwrap();
+        let read_result = || {
+            let result = fs::read_to_string(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+            serde_json::from_str::<serde_json::Value>(&result).unwrap()
+        };
+        let args = [
+            "--result-file",
+            result_file,
+            "--billing-code",
+            "1",
+            "--max-errors",
+            "1",
+            "--quiet",
+        ];
+        process(input.as_bytes(), io::sink(), &options(&args)).unwrap();
+        assert_eq!(
+            read_result(),
+            json!({"read": 3, "passed": 1, "rejected": 2, "errors": 1})
+        );
+        let args = ["--result-file", result_file, "--error-on-empty-input"];
+        assert!(process("".as_bytes(), io::sink(), &options(&args)).is_err());
+        assert_eq!(
+            read_result(),
+            json!({"read": 0, "passed": 0, "rejected": 0, "errors": 0})
+        );
+    }
+
+    #[test]
+    fn stats_are_written_to_file() {
+        let input = [
+            line("alpha", "1", &[10.0]),
+            line("beta", "1", &[20.0]),
+            line("gamma", "2", &[40.0]),
+        ]
+        .concat();
+        let path = std::env::temp_dir().join(format!("etl-stats-{}.json", std::process::id()));
+        let options = options(&["--stats-file", path.to_str().unwrap()]);
+        process(input.as_bytes(), io::sink(), &options).unwrap();
+        let stats = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&stats).unwrap(),
+            json!({
+                "read": 3,
+                "written": 2,
+                "distinct_billing_codes": 1,
+                "avg_rate": {
+                    "count": 2,
+                    "min": 10.0,
+                    "max": 20.0,
+                    "mean": 15.0,
+                    "std_dev": 50f64.sqrt(),
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn averages_outside_expected_ranges_are_reported() {
+        let input = [
+            line("alpha", "1", &[10.0]),
+            line("beta", "1", &[25.0]),
+            line("gamma", "2", &[100.0]),
+            line("delta", "1", &[]),
+        ]
+        .concat();
+        let error = run(&input, &["--expect-range", "1,5,20"]).unwrap_err();
+        assert_eq!(
+            format!("{error:#}"),
+            "2 records with an average rate outside the expected range:\n\
+             line 2: beta (1) has average rate 25 (expected 5 to 20)\n\
+             line 4: delta (1) has no rate (expected 5 to 20)"
+        );
+        let first_two = [line("alpha", "1", &[10.0]), line("beta", "1", &[25.0])].concat();
+        assert!(run(&first_two, &["--expect-range", "1,10,25"]).is_ok());
+    }
+
+    #[test]
+    fn expected_ranges_must_be_well_formed() {
+        assert!("1,5".parse::<ExpectedRange>().is_err());
+        assert!("1,low,20".parse::<ExpectedRange>().is_err());
+        assert_eq!(
+            " 1 , 5, 20 ".parse::<ExpectedRange>().unwrap(),
+            ExpectedRange {
+                billing_code: "1".into(),
+                min: 5.0,
+                max: 20.0,
+            }
+        );
+    }
+
+    #[test]
+    fn checkpoint_records_processed_input_and_written_output() {
+        let first = line("alpha", "1", &[10.0]);
+        let input = [first.clone(), line("beta", "2", &[20.0])].concat();
+        let path = std::env::temp_dir().join(format!("etl-checkpoint-{}.json", std::process::id()));
+        let options = options(&["--output-comment", "rates"]);
+
+        let mut output = Vec::new();
+        process_checkpointed(
+            input.as_bytes(),
+            &mut output,
+            &path,
+            Checkpoint::default(),
+            &options,
+        )
+        .unwrap();
+        let checkpoint = Checkpoint::read(&path).unwrap();
+        assert_eq!(
+            checkpoint,
+            Checkpoint {
+                input_offset: input.len() as u64,
+                output_offset: output.len() as u64,
+            }
+        );
+
+        // Resuming after the first record continues without the preamble
+        let header = "#rates\nname,billing_code,avg_rate\n";
+        let start = Checkpoint {
+            input_offset: first.len() as u64,
+            output_offset: (header.len() + "alpha,1,10.0\n".len()) as u64,
+        };
+        let mut resumed = Vec::new();
+        process_checkpointed(
+            &input.as_bytes()[first.len()..],
+            &mut resumed,
+            &path,
+            start,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(resumed).unwrap(), "beta,2,20.0\n");
+        assert_eq!(Checkpoint::read(&path).unwrap(), checkpoint);
+
+        // Resuming before the first row was written still writes the header, and the preamble
+        // unless it was written
+        for (output_offset, expected) in [(0, "#rates\n"), ("#rates\n".len(), "")] {
+            let start = Checkpoint {
+                input_offset: 0,
+                output_offset: output_offset as u64,
+            };
+            let mut resumed = Vec::new();
+            process_checkpointed(input.as_bytes(), &mut resumed, &path, start, &options).unwrap();
+            assert_eq!(
+                String::from_utf8(resumed).unwrap(),
+                format!("{expected}name,billing_code,avg_rate\nalpha,1,10.0\nbeta,2,20.0\n")
+            );
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn checkpoints_taken_within_multi_object_lines_resume_at_line_start() {
+        /// Output failing once a row of `gamma`, from the middle of a line, reaches it
+        struct FailingOutput(Vec<u8>);
+
+        impl Write for FailingOutput {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if buf.windows(5).any(|w| w == b"gamma") {
+                    return Err(io::Error::other("disk full"));
+                }
+                self.0.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let first = line("alpha", "1", &[10.0]);
+        let second = [
+            line("beta", "2", &[20.0]).trim_end(),
+            &line("gamma", "3", &[5.0]),
+        ]
+        .concat();
+        let input = [first.clone(), second, line("delta", "4", &[1.0])].concat();
+        let path = std::env::temp_dir().join(format!("etl-midline-{}.json", std::process::id()));
+        let options = options(&["--multi-object-lines"]);
+        let offset = Rc::new(Cell::new(0));
+        let reader = OffsetReader {
+            inner: input.as_bytes(),
+            offset: offset.clone(),
+        };
+        let mut failing = FailingOutput(Vec::new());
+        let output = RowWriter::new(&mut failing, None, Resume::Start, &options).unwrap();
+        let checkpointer = Checkpointer {
+            path: &path,
+            start: Checkpoint::default(),
+            input_offset: offset,
+            line: 0,
+            line_end: 0,
+            last: Instant::now(),
+            interval: Duration::ZERO,
+        };
+        let failed = process_with(reader, output, Some(checkpointer), None, &options);
+        let checkpoint = Checkpoint::read(&path);
+        assert!(failed.is_err());
+        let checkpoint = checkpoint.unwrap();
+        let header = "name,billing_code,avg_rate\n";
+        assert_eq!(
+            checkpoint,
+            Checkpoint {
+                input_offset: first.len() as u64,
+                output_offset: (header.len() + "alpha,1,10.0\n".len()) as u64,
+            }
+        );
+
+        let mut resumed = Vec::new();
+        let rest = &input.as_bytes()[first.len()..];
+        let resumed_run = process_checkpointed(rest, &mut resumed, &path, checkpoint, &options);
+        fs::remove_file(&path).unwrap();
+        resumed_run.unwrap();
+        assert_eq!(
+            String::from_utf8(resumed).unwrap(),
+            "beta,2,20.0\ngamma,3,5.0\ndelta,4,1.0\n"
+        );
+    }
+
+    #[test]
+    fn zero_rates_are_handled_by_policy() {
+        let input = [line("a", "1", &[0.0, 10.0, 20.0]), line("b", "2", &[0.0])].concat();
+        let actual = run(&input, &["--include-null-rate"]).unwrap();
+        assert_eq!(actual, "name,billing_code,avg_rate\na,1,10.0\nb,2,0.0\n");
+        let args = ["--include-null-rate", "--zero-rate-policy", "exclude"];
+        assert_eq!(
+            run(&input, &args).unwrap(),
+            "name,billing_code,avg_rate\na,1,15.0\nb,2,\n"
+        );
+        let error = run(&input, &["--zero-rate-policy", "error"]).unwrap_err();
+        assert!(
+            format!("{error:#}")
+                .starts_with("error on line 1: failed to parse record: negotiated rate is zero"),
+            "{error:#}"
+        );
+    }
+
+    #[test]
+    fn large_integer_rates_are_rejected_on_request() {
+        let rate = (1u64 << 53) + 1;
+        let input = format!(
+            "{}{{\"name\":\"b\",\"billing_code\":\"2\",\"negotiated_rates\":[{{\"negotiated_prices\":[{{\"negotiated_rate\":{rate}}}]}}]}}\n",
+            line("a", "1", &[9007199254740992.0]),
+        );
+        let args = ["--max-rate", "1e20"];
+        assert_eq!(
+            run(&input, &args).unwrap(),
+            "name,billing_code,avg_rate\na,1,9007199254740992.0\nb,2,9007199254740992.0\n"
+        );
+        let error = run(&input, &["--max-rate", "1e20", "--strict-numbers"]).unwrap_err();
+        assert!(
+            format!("{error:#}").starts_with(&format!(
+                "error on line 2: failed to parse record: negotiated rate {rate} is not exactly \
+                representable"
+            )),
+            "{error:#}"
+        );
+        let beyond_u64 = input.replace(&rate.to_string(), "100000000000000000000");
+        for args in [
+            &["--strict-numbers"][..],
+            &["--strict-numbers", "--project", "x=/x"],
+        ] {
+            let args = [&["--max-rate", "1e21"], args].concat();
+            let error = run(&beyond_u64, &args).unwrap_err();
+            assert!(
+                format!("{error:#}").contains("negotiated rate 100000000000000000000 is not"),
+                "{error:#}"
+            );
+        }
+        assert!(run(&beyond_u64, &["--max-rate", "1e21"]).is_ok());
+    }
+
+    #[test]
+    fn groups_with_too_few_prices_are_ignored() {
+        let input = json!({
+            "name": "alpha",
+            "billing_code": "1",
+            "negotiated_rates": [
+                {
+                    "negotiated_prices": [
+                        {
+                            "negotiated_rate": 10,
+                        },
+                        {
+                            "negotiated_rate": 20,
+                        },
+                    ],
+                },
+                {
+                    "negotiated_prices": [
+                        {
+                            "negotiated_rate": 100,
+                        },
+                    ],
+                },
+                {
+                    "negotiated_prices": [
+                        {
+                            "negotiated_rate": 30,
+                        },
+                        {
+                            "negotiated_rate": 40,
+                        },
+                        {
+                            "negotiated_rate": 50,
+                        },
+                    ],
+                },
+            ],
+        });
+        let rate = RecordSeed {
+            options: &options(&["--min-prices-per-group", "2"]),
+        }
+        .deserialize(input)
+        .unwrap()
+        .1;
+        assert_eq!(rate.count(), 5);
+        assert_eq!(rate.average(), Some(30.0));
+    }
+
+    #[test]
+    fn group_sizes_are_counted_into_histogram() {
+        let input = json!({
+            "name": "alpha",
+            "billing_code": "1",
+            "negotiated_rates": [
+                {
+                    "negotiated_prices": [
+                        {
+                            "negotiated_rate": 10,
+                        },
+                        {
+                            "negotiated_rate": 20,
+                        },
+                    ],
+                },
+                {
+                    "negotiated_prices": [],
+                },
+            ],
+        });
+        let rate = RecordSeed {
+            options: &options(&["--group-size-histogram", "--max-prices-per-group", "1"]),
+        }
+        .deserialize(input)
+        .unwrap()
+        .1;
+        assert_eq!(rate.group_sizes, Some(vec![2, 0]));
+        let histogram = BTreeMap::from([(0, 1), (2, 3)]);
+        assert_eq!(
+            group_size_histogram(&histogram),
+            "prices per group: groups\n0: 1\n2: 3\n"
+        );
+    }
+
+    #[test]
+    fn passthrough_fields_are_appended_to_rows() {
+        let input = [
+            r#"{"name":"alpha","billing_code":"1","plan_id":7,"network":"north","negotiated_rates":[]}"#,
+            r#"{"name":"beta","billing_code":"2","negotiated_rates":[]}"#,
+        ]
+        .join("\n");
+        let args = [
+            "--passthrough-fields",
+            "network,plan_id",
+            "--include-null-rate",
+            "--include-rejected",
+            "--annotate-status",
+        ];
+        let actual = run(&input, &args).unwrap();
+        assert_eq!(
+            actual,
+            "name,billing_code,avg_rate,network,plan_id,status\n\
+             alpha,1,,north,7,kept\n\
+             beta,2,,,,kept\n"
+        );
+        let mut schema = Vec::new();
+        write_schema(&mut schema, &options(&args)).unwrap();
+        let header = actual.lines().next().unwrap();
+        assert_eq!(String::from_utf8(schema).unwrap(), format!("{header}\n"));
+    }
+}
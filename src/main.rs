@@ -1,91 +1,771 @@
 use anyhow::Context;
-use clap::Parser;
-use serde::{Deserialize, Serialize};
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, parser::ValueSource};
+use flate2::bufread::MultiGzDecoder;
+use notify::{EventKind, RecursiveMode, Watcher};
+use rust_etl_code_test::{
+    Checkpoint, InputSummary, Inputs, OutputFormat, ProcessOptions, Profiled, Stage, combine_csv,
+    probe, process, process_checkpointed, process_inputs, process_parallel,
+    process_parallel_prefetched, process_split, process_verified, write_config, write_schema,
+};
+use serde_json::Value;
 use std::{
-    fs::File,
-    io::{BufRead, BufReader, BufWriter, Write},
-    ops::Add,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, IsTerminal, Read, Seek, SeekFrom, Write},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+use tar::Archive;
+
+#[cfg(unix)]
+use std::os::{
+    fd::{FromRawFd, RawFd},
+    unix::net::UnixStream,
 };
 
-#[derive(Debug, Deserialize, PartialEq, Serialize)]
-struct Record {
-    name: String,
-    billing_code: String,
-    #[serde(
-        deserialize_with = "serdapt::From::<AccumulatedRate, serdapt::Fold<NegotiatedRate, AccumulatedRate>>::deserialize",
-        rename(deserialize = "negotiated_rates")
+/// Extract billing information from JSONL input and outputs records in CSV or JSON format
+///
+/// Every option can also be set with an `ETL_`-prefixed environment variable (e.g. `ETL_INPUT`),
+/// and processing options in a `--config` file. Command-line flags take precedence over
+/// environment variables, which take precedence over the config file, which takes precedence over
+/// defaults.
+#[derive(Debug, Parser)]
+struct Cli {
+    /// Input file to read records from (defaults to stdin), decompressed if gzipped
+    ///
+    /// Can be repeated to read several inputs one after the other. A tar archive is read as the
+    /// sequence of its `.jsonl` members, other members being skipped.
+    #[arg(short, long, env = "ETL_INPUT")]
+    input: Vec<PathBuf>,
+    /// Inherited file descriptor to read records from instead of stdin, decompressed if gzipped
+    #[cfg(unix)]
+    #[arg(long, env = "ETL_INPUT_FD", conflicts_with = "input")]
+    input_fd: Option<RawFd>,
+    /// Output file to write CSV to (defaults to stdout)
+    ///
+    /// On Unix, `unix:<path>` streams the output to the Unix domain socket at `<path>` instead,
+    /// failing if the peer closes it before the end.
+    #[arg(short, long, env = "ETL_OUTPUT")]
+    output: Option<PathBuf>,
+    /// Process input without writing output and report throughput on stderr
+    #[arg(long, env = "ETL_BENCHMARK", conflicts_with_all = ["output", "quiet"])]
+    benchmark: bool,
+    /// Split output across files of about this many bytes, named after the output file with a
+    /// part number (e.g. `output.000.csv`)
+    #[arg(
+        long,
+        env = "ETL_ROTATE_BYTES",
+        requires = "output",
+        conflicts_with = "max_output_bytes"
+    )]
+    rotate_bytes: Option<u64>,
+    /// Read the CSV output file back once written and fail if it does not parse into the rows
+    /// that were written, e.g. because of quoting
+    ///
+    /// Written rows are held in memory until they are verified.
+    #[arg(
+        long,
+        env = "ETL_VERIFY_ROUNDTRIP",
+        requires = "output",
+        conflicts_with_all = ["rotate_bytes", "manifest", "checkpoint", "jobs"],
+    )]
+    verify_roundtrip: bool,
+    /// Write the output file to a temporary file next to it, renamed over it once processing
+    /// succeeds and removed otherwise, so that a partial output is never left in its place
+    #[arg(
+        long,
+        env = "ETL_ATOMIC_OUTPUT",
+        requires = "output",
+        conflicts_with_all = ["rotate_bytes", "checkpoint"],
+    )]
+    atomic_output: bool,
+    /// File to write a copy of the output to while also writing it to stdout
+    #[arg(long, env = "ETL_TEE", conflicts_with_all = ["output", "benchmark"])]
+    tee: Option<PathBuf>,
+    /// File to periodically record the progress of processing in, to resume it with `--resume`
+    ///
+    /// Requires uncompressed `--input` and `--output` files.
+    #[arg(
+        long,
+        env = "ETL_CHECKPOINT",
+        requires_all = ["input", "output"],
+        conflicts_with_all = [
+            "benchmark",
+            "rotate_bytes",
+            "tee",
+            "max_output_bytes",
+            "manifest",
+            "append_summary_row",
+            "rolling_window",
+            "expected_codes",
+            "outliers",
+        ],
+    )]
+    checkpoint: Option<PathBuf>,
+    /// Resume processing from the `--checkpoint` left by a previous run, appending to its output
+    #[arg(long, env = "ETL_RESUME", requires = "checkpoint")]
+    resume: bool,
+    /// File to write the number of records read, written and failed for each input to as JSON
+    #[arg(long, env = "ETL_MANIFEST", conflicts_with_all = ["benchmark", "rotate_bytes"])]
+    manifest: Option<PathBuf>,
+    /// Process `--input` again whenever it changes, until interrupted with Ctrl-C
+    ///
+    /// Each run writes a fresh `--output`. Errors are reported without ending the watch.
+    #[arg(
+        long,
+        env = "ETL_WATCH",
+        requires = "input",
+        conflicts_with_all = ["benchmark", "checkpoint"],
+    )]
+    watch: bool,
+    /// Process this many `--input` files in parallel, each on its own, writing their outputs in
+    /// order
+    ///
+    /// Options applying to the whole input, like `--max-errors`, apply to each file separately.
+    #[arg(
+        long,
+        env = "ETL_JOBS",
+        requires = "input",
+        conflicts_with_all = [
+            "benchmark",
+            "checkpoint",
+            "rotate_bytes",
+            "manifest",
+            "watch",
+            "count_only",
+            "emit_every",
+            "expect",
+            "max_output_bytes",
+            "stats_file",
+            "result_file",
+            "progress",
+            "error_on_empty_input",
+            "append_summary_row",
+            "rolling_window",
+            "expected_codes",
+            "outliers",
+        ],
     )]
-    avg_rate: Option<f64>,
+    jobs: Option<NonZeroUsize>,
+    /// Number of `--input` files read at the same time with `--jobs`, on threads of their own,
+    /// ahead of their decompression and parsing
+    ///
+    /// By default, each input is read by the thread processing it. A value above `--jobs` keeps
+    /// reading inputs, e.g. from network storage, while others are processed, up to 1 MiB being
+    /// read ahead of the processing of each input.
+    #[arg(
+        long,
+        env = "ETL_IO_CONCURRENCY",
+        alias = "max-concurrency",
+        requires = "jobs"
+    )]
+    io_concurrency: Option<NonZeroUsize>,
+    /// Print the header of the output for the given options and exit without reading input
+    #[arg(long, env = "ETL_PRINT_SCHEMA")]
+    print_schema: bool,
+    /// Print the processing options in effect, as resolved from flags, environment variables and
+    /// defaults, to stderr as JSON before processing
+    #[arg(long, env = "ETL_PRINT_CONFIG")]
+    print_config: bool,
+    /// Like `--print-config`, but exit without reading input
+    #[arg(long, env = "ETL_PRINT_CONFIG_ONLY", conflicts_with = "print_config")]
+    print_config_only: bool,
+    /// Parse this many lines of input into records, report the fields found in them on stdout and
+    /// exit without writing output
+    #[arg(
+        long,
+        env = "ETL_PROBE",
+        value_name = "LINES",
+        conflicts_with_all = ["print_schema", "watch", "jobs", "checkpoint", "benchmark", "output"],
+    )]
+    probe: Option<u64>,
+    /// CSV outputs of previous runs to combine into one with a single header, written to
+    /// `--output` or stdout, instead of processing input
+    ///
+    /// Every file must have the same header, and rows are written in the order of the files.
+    #[arg(
+        long,
+        env = "ETL_COMBINE_INPUTS",
+        num_args = 1..,
+        value_name = "CSV",
+        conflicts_with_all = [
+            "input",
+            "probe",
+            "print_schema",
+            "watch",
+            "jobs",
+            "checkpoint",
+            "benchmark",
+            "tee",
+            "rotate_bytes",
+            "manifest",
+        ],
+    )]
+    combine_inputs: Vec<PathBuf>,
+    /// Report the findings of `--probe` as JSON
+    #[arg(long, env = "ETL_JSON", requires = "probe")]
+    json: bool,
+    /// JSON file, or `-` for stdin, with an object setting processing options not set by flags
+    /// or environment variables
+    ///
+    /// Keys are option names as printed by `--print-config`, e.g. `max_rate`, and values are
+    /// written as on the command line, as strings, numbers or booleans, or arrays of them for
+    /// options that can be repeated. Reading the config from stdin requires `--input` or
+    /// `--input-fd`.
+    #[arg(long, env = "ETL_CONFIG")]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    options: ProcessOptions,
 }
 
-#[derive(Debug, Default)]
-struct AccumulatedRate {
-    rate: f64,
-    count: u64,
+fn main() -> anyhow::Result<()> {
+    let cli = parse_cli()?;
+    if cli.print_config || cli.print_config_only {
+        write_config(io::stderr().lock(), &cli.options)?;
+        if cli.print_config_only {
+            return Ok(());
+        }
+    }
+    if cli.print_schema {
+        return write_schema(std::io::stdout().lock(), &cli.options);
+    }
+    if !cli.combine_inputs.is_empty() {
+        return combine(&cli);
+    }
+    if let (Some(checkpoint), Some(output)) = (&cli.checkpoint, &cli.output) {
+        let [input] = cli.input.as_slice() else {
+            anyhow::bail!("--checkpoint requires a single --input");
+        };
+        return run_checkpointed(input, output, checkpoint, &cli);
+    }
+    let arrow = cli.options.format == OutputFormat::Arrow;
+    if arrow && cli.output.is_none() && !cli.benchmark && io::stdout().is_terminal() {
+        anyhow::bail!("refusing to write Arrow to a terminal, redirect stdout or use --output");
+    }
+    if cli.watch {
+        return watch(&cli);
+    }
+    if let Some(jobs) = cli.jobs {
+        return run_parallel(&cli, jobs);
+    }
+    let mut sources = if cli.input.is_empty() {
+        vec![default_source(&cli)?]
+    } else {
+        cli.input
+            .iter()
+            .map(|p| open_source(p.display().to_string(), open_input(p)?))
+            .collect::<anyhow::Result<_>>()?
+    };
+    let input = Inputs::streamed(jsonl_inputs(&mut sources));
+    if let Some(lines) = cli.probe {
+        let report = probe(input, lines, &cli.options)?;
+        let mut output = io::stdout().lock();
+        if cli.json {
+            serde_json::to_writer_pretty(&mut output, &report)?;
+            writeln!(output)?;
+        } else {
+            write!(output, "{}", report.to_text())?;
+        }
+        return Ok(());
+    }
+    run(input, &cli)
 }
 
-impl From<AccumulatedRate> for Option<f64> {
-    fn from(value: AccumulatedRate) -> Self {
-        if value.count == 0 {
-            None
-        } else {
-            Some(value.rate / value.count as f64)
+/// Parses the command line, with the options of `--config` that are not set otherwise
+fn parse_cli() -> anyhow::Result<Cli> {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let Some(path) = &cli.config else {
+        return Ok(cli);
+    };
+    let config = if path == Path::new("-") {
+        #[cfg(unix)]
+        let input_fd = cli.input_fd.is_some();
+        #[cfg(not(unix))]
+        let input_fd = false;
+        if cli.input.is_empty() && !input_fd {
+            anyhow::bail!("--config - requires --input or --input-fd, as stdin holds the config");
+        }
+        io::read_to_string(io::stdin()).context("failed to read config from stdin")?
+    } else {
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?
+    };
+    let args = config_args(&config, &matches).context("invalid config")?;
+    Ok(Cli::parse_from(
+        std::env::args_os().chain(args.into_iter().map(OsString::from)),
+    ))
+}
+
+/// Returns the command-line arguments setting the processing options of `config`, a JSON object
+/// mapping option names to values, that `matches` did not get from flags or environment variables
+fn config_args(config: &str, matches: &ArgMatches) -> anyhow::Result<Vec<String>> {
+    let config: serde_json::Map<String, Value> = serde_json::from_str(config)?;
+    let command = ProcessOptions::augment_args(clap::Command::new("config"));
+    let mut args = Vec::new();
+    for (key, value) in config {
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == key.as_str())
+        else {
+            anyhow::bail!("unknown option `{key}`");
+        };
+        let flag = format!("--{}", arg.get_long().unwrap_or(&key));
+        let values = match value {
+            Value::Array(values) => values,
+            value => vec![value],
+        };
+        let mut option_args = Vec::new();
+        for value in values {
+            match value {
+                Value::Null | Value::Bool(false) => {}
+                Value::Bool(true) => option_args.push(flag.clone()),
+                Value::String(s) => option_args.push(format!("{flag}={s}")),
+                Value::Number(n) => option_args.push(format!("{flag}={n}")),
+                Value::Array(_) | Value::Object(_) => anyhow::bail!(
+                    "invalid value of `{key}`: expected a string, number or boolean, or an \
+                    array of them"
+                ),
+            }
+        }
+        let set = matches!(
+            matches.value_source(&key),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        );
+        if !set {
+            args.extend(option_args);
         }
     }
+    Ok(args)
 }
 
-impl Add<NegotiatedRate> for AccumulatedRate {
-    type Output = Self;
+fn run<N, I>(input: Inputs<N, I>, cli: &Cli) -> anyhow::Result<()>
+where
+    N: Iterator<Item = io::Result<(String, I)>>,
+    I: BufRead,
+{
+    let options = &cli.options;
+    if cli.benchmark {
+        return benchmark(input, options);
+    }
+    if let Some(manifest) = &cli.manifest {
+        let inputs = match (&cli.output, &cli.tee) {
+            (Some(output), _) => write_output(output, cli.atomic_output, |writer, _| {
+                process_inputs(input, writer, options)
+            })?,
+            (None, Some(tee)) => {
+                let output = Tee(std::io::stdout().lock(), open_output(tee)?);
+                process_inputs(input, output, options)?
+            }
+            (None, None) => process_inputs(input, std::io::stdout().lock(), options)?,
+        };
+        return write_manifest(manifest, &inputs);
+    }
+    if let Some(tee) = &cli.tee {
+        let output = Tee(std::io::stdout().lock(), open_output(tee)?);
+        return process(input, output, options).map(drop);
+    }
+    match (&cli.output, cli.rotate_bytes) {
+        (None, _) => process(input, std::io::stdout().lock(), options),
+        (Some(output), None) if cli.verify_roundtrip => {
+            if output.to_str().is_some_and(|p| p.starts_with("unix:")) {
+                anyhow::bail!("--verify-roundtrip requires an output file");
+            }
+            write_output(output, cli.atomic_output, |writer, path| {
+                let written = || {
+                    let file = File::open(path)
+                        .with_context(|| format!("failed to open {}", path.display()))?;
+                    Ok(BufReader::new(file))
+                };
+                process_verified(input, writer, written, options)
+            })
+        }
+        (Some(output), None) => write_output(output, cli.atomic_output, |writer, _| {
+            process(input, writer, options)
+        }),
+        (Some(output), Some(max_bytes)) => process_split(
+            input,
+            max_bytes,
+            |part| open_output(&part_path(output, part)),
+            options,
+        ),
+    }
+    .map(drop)
+}
 
-    fn add(self, rhs: NegotiatedRate) -> Self::Output {
-        Self {
-            rate: self.rate + rhs.negotiated_prices.rate,
-            count: self.count + rhs.negotiated_prices.count,
+fn run_parallel(cli: &Cli, jobs: NonZeroUsize) -> anyhow::Result<()> {
+    let names = cli
+        .input
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<_>>();
+    let options = &cli.options;
+    let process = |output: &mut dyn Write| match cli.io_concurrency {
+        None => {
+            let open = |i: usize| parallel_input(names[i].clone(), open_input(&cli.input[i])?);
+            process_parallel(&names, open, output, jobs, options)
+        }
+        Some(io_concurrency) => {
+            let read = |i: usize| {
+                let p = &cli.input[i];
+                File::open(p).with_context(|| format!("failed to open {}", p.display()))
+            };
+            let open = |i: usize, input| parallel_input(names[i].clone(), BufReader::new(input));
+            process_parallel_prefetched(&names, read, open, output, jobs, io_concurrency, options)
         }
+    };
+    match (&cli.output, &cli.tee) {
+        (Some(output), _) => write_output(output, cli.atomic_output, |mut writer, _| {
+            process(&mut writer)
+        }),
+        (None, Some(tee)) => process(&mut Tee(std::io::stdout().lock(), open_output(tee)?)),
+        (None, None) => process(&mut std::io::stdout().lock()),
     }
+    .map(drop)
 }
 
-impl Add<NegotiatedPrice> for AccumulatedRate {
-    type Output = Self;
+/// Returns the JSONL records of an input processed with `--jobs`, decompressed if gzipped
+fn parallel_input<'a, R>(name: String, input: R) -> anyhow::Result<Box<dyn BufRead + 'a>>
+where
+    R: BufRead + 'a,
+{
+    let Source::Jsonl(_, Some(input)) = open_source(name, input)? else {
+        anyhow::bail!("--jobs does not support tar archives");
+    };
+    Ok(input)
+}
+
+fn combine(cli: &Cli) -> anyhow::Result<()> {
+    let inputs = cli
+        .combine_inputs
+        .iter()
+        .map(|p| Ok((p.display().to_string(), open_input(p)?)))
+        .collect::<anyhow::Result<_>>()?;
+    match &cli.output {
+        Some(output) => write_output(output, cli.atomic_output, |writer, _| {
+            combine_csv(inputs, writer, &cli.options)
+        }),
+        None => combine_csv(inputs, std::io::stdout().lock(), &cli.options),
+    }
+    .map(drop)
+}
+
+fn run_checkpointed(
+    input: &Path,
+    output: &Path,
+    checkpoint: &Path,
+    cli: &Cli,
+) -> anyhow::Result<()> {
+    let mut input_file =
+        File::open(input).with_context(|| format!("failed to open {}", input.display()))?;
+    let mut magic = Vec::new();
+    (&mut input_file)
+        .take(GZIP_MAGIC.len() as u64)
+        .read_to_end(&mut magic)
+        .with_context(|| format!("failed to read {}", input.display()))?;
+    if magic == GZIP_MAGIC {
+        anyhow::bail!("--checkpoint is not supported with compressed input");
+    }
+    let start = if cli.resume {
+        Checkpoint::read(checkpoint)?
+    } else {
+        Checkpoint::default()
+    };
+    input_file.seek(SeekFrom::Start(start.input_offset))?;
+    let output_file = if cli.resume {
+        let file = OpenOptions::new()
+            .append(true)
+            .open(output)
+            .with_context(|| format!("failed to open {}", output.display()))?;
+        // Drop output written after the checkpoint, as its input is processed again
+        file.set_len(start.output_offset)
+            .with_context(|| format!("failed to truncate {}", output.display()))?;
+        file
+    } else {
+        File::create(output).with_context(|| format!("failed to open {}", output.display()))?
+    };
+    process_checkpointed(
+        BufReader::new(input_file),
+        BufWriter::new(output_file),
+        checkpoint,
+        start,
+        &cli.options,
+    )
+    .map(drop)
+}
+
+fn write_manifest(p: &Path, inputs: &[InputSummary]) -> anyhow::Result<()> {
+    let mut output = open_output(p)?;
+    serde_json::to_writer_pretty(&mut output, inputs)
+        .map_err(io::Error::from)
+        .and_then(|()| writeln!(output))
+        .and_then(|()| output.flush())
+        .with_context(|| format!("failed to write {}", p.display()))
+}
+
+/// Time without changes to wait for before processing a changed input again
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
 
-    fn add(self, rhs: NegotiatedPrice) -> Self::Output {
-        Self {
-            rate: self.rate + rhs.negotiated_rate,
-            count: self.count + 1,
+enum WatchMessage {
+    Event(notify::Result<notify::Event>),
+    Interrupted,
+}
+
+fn watch(cli: &Cli) -> anyhow::Result<()> {
+    let [input] = cli.input.as_slice() else {
+        anyhow::bail!("--watch requires a single --input");
+    };
+    let (sender, messages) = mpsc::channel();
+    let events = sender.clone();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = events.send(WatchMessage::Event(event));
+    })
+    .context("failed to watch input")?;
+    // Watch the directory as editors often replace files instead of writing to them
+    let dir = input
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .with_context(|| format!("failed to watch {}", dir.display()))?;
+    ctrlc::set_handler(move || {
+        let _ = sender.send(WatchMessage::Interrupted);
+    })
+    .context("failed to handle Ctrl-C")?;
+    let run_once = || {
+        let source = open_input(input).and_then(|i| open_source(input.display().to_string(), i));
+        let result = source.and_then(|source| {
+            let mut sources = [source];
+            run(Inputs::streamed(jsonl_inputs(&mut sources)), cli)
+        });
+        if let Err(e) = result {
+            eprintln!("error: {e:#}");
+        }
+    };
+    run_once();
+    loop {
+        match messages.recv() {
+            Ok(WatchMessage::Event(event)) if changes(input, &event) => {}
+            Ok(WatchMessage::Event(_)) => continue,
+            Ok(WatchMessage::Interrupted) | Err(_) => return Ok(()),
         }
+        loop {
+            match messages.recv_timeout(WATCH_DEBOUNCE) {
+                Ok(WatchMessage::Event(_)) => {}
+                Ok(WatchMessage::Interrupted) | Err(RecvTimeoutError::Disconnected) => {
+                    return Ok(());
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+            }
+        }
+        run_once();
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct NegotiatedRate {
-    #[serde(with = "serdapt::Fold::<NegotiatedPrice, AccumulatedRate>")]
-    negotiated_prices: AccumulatedRate,
+/// Returns whether `event` is a change to the file at `input`
+fn changes(input: &Path, event: &notify::Result<notify::Event>) -> bool {
+    let Ok(event) = event else {
+        return false;
+    };
+    let modifies = matches!(
+        event.kind,
+        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+    );
+    modifies
+        && event
+            .paths
+            .iter()
+            .any(|p| p.file_name() == input.file_name())
 }
 
-#[derive(Debug, Deserialize)]
-struct NegotiatedPrice {
-    negotiated_rate: f64,
+/// Returns the path of a part of split output, inserting the part number before the extension
+fn part_path(p: &Path, part: u64) -> PathBuf {
+    let mut name = p.file_stem().unwrap_or_default().to_owned();
+    name.push(format!(".{part:03}"));
+    if let Some(extension) = p.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    p.with_file_name(name)
 }
 
-/// Extract billing information from JSONL input and outputs records in CSV format
-#[derive(Debug, Parser)]
-struct Cli {
-    /// Input file to read JSONL from (defaults to stdin)
-    #[arg(short, long)]
-    input: Option<PathBuf>,
-    /// Output file to write CSV to (defaults to stdout)
-    #[arg(short, long)]
-    output: Option<PathBuf>,
+fn benchmark<I>(input: I, options: &ProcessOptions) -> anyhow::Result<()>
+where
+    I: BufRead,
+{
+    let mut input = CountingReader::new(input);
+    let start = Instant::now();
+    let summary = process(&mut input, io::sink(), options)?;
+    let seconds = start.elapsed().as_secs_f64();
+    eprintln!("records: {}", summary.read);
+    eprintln!("bytes: {}", input.count);
+    eprintln!("time: {seconds:.3}s");
+    eprintln!(
+        "throughput: {:.1} records/s, {:.2} MB/s",
+        summary.read as f64 / seconds,
+        input.count as f64 / 1e6 / seconds,
+    );
+    Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
-    match (cli.input, cli.output) {
-        (None, None) => process(std::io::stdin().lock(), std::io::stdout().lock()),
-        (None, Some(output)) => process(std::io::stdin().lock(), open_output(&output)?),
-        (Some(input), None) => process(open_input(&input)?, std::io::stdout().lock()),
-        (Some(input), Some(output)) => process(open_input(&input)?, open_output(&output)?),
+/// Writer duplicating everything written to both inner writers
+struct Tee<A, B>(A, B);
+
+impl<A: Write, B: Write> Write for Tee<A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_all(buf)?;
+        self.1.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.1.flush()
+    }
+}
+
+/// Reader wrapper counting the bytes consumed from the inner reader
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R> CountingReader<R> {
+    fn new(inner: R) -> Self {
+        Self { inner, count: 0 }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt);
+        self.count += amt as u64;
+    }
+}
+
+/// Magic bytes starting a gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Magic bytes of a tar archive, found at `TAR_MAGIC_OFFSET` in its first header
+const TAR_MAGIC: &[u8] = b"ustar";
+const TAR_MAGIC_OFFSET: usize = 257;
+
+/// Input given on the command line, either read as JSONL or as a tar archive of JSONL members
+enum Source<'a> {
+    /// Named JSONL input, taken once it is read
+    Jsonl(String, Option<Box<dyn BufRead + 'a>>),
+    Tar(Archive<Box<dyn BufRead + 'a>>),
+}
+
+/// Returns the source of records read from `input`, decompressed if gzipped
+fn open_source<'a, R>(name: String, input: R) -> anyhow::Result<Source<'a>>
+where
+    R: BufRead + 'a,
+{
+    let (header, input) = sniff(decompress_gzip(input)?, TAR_MAGIC_OFFSET + TAR_MAGIC.len())?;
+    let input: Box<dyn BufRead + 'a> = Box::new(input);
+    if header.get(TAR_MAGIC_OFFSET..) == Some(TAR_MAGIC) {
+        Ok(Source::Tar(Archive::new(input)))
+    } else {
+        Ok(Source::Jsonl(name, Some(input)))
+    }
+}
+
+/// Returns the source of records read without `--input`, from `--input-fd` or stdin
+fn default_source(cli: &Cli) -> anyhow::Result<Source<'static>> {
+    #[cfg(unix)]
+    if let Some(fd) = cli.input_fd {
+        // SAFETY: The descriptor is handed to this process to read input from and not used
+        // otherwise, so it can be owned by the file.
+        let input = unsafe { File::from_raw_fd(fd) };
+        return open_source(format!("fd {fd}"), BufReader::new(input));
+    }
+    open_source("-".into(), std::io::stdin().lock())
+}
+
+/// Named input read as JSONL, or the error getting it from its source
+type JsonlInput<'a> = io::Result<(String, Box<dyn BufRead + 'a>)>;
+
+/// Returns the JSONL inputs of `sources`, reading the `.jsonl` members of each archive in turn
+fn jsonl_inputs<'s, 'a: 's>(
+    sources: &'s mut [Source<'a>],
+) -> impl Iterator<Item = JsonlInput<'s>> + 's {
+    sources
+        .iter_mut()
+        .flat_map(|source| -> Box<dyn Iterator<Item = JsonlInput<'s>> + 's> {
+            match source {
+                Source::Jsonl(name, input) => {
+                    let input = input.take().map(|input| Ok((name.clone(), input)));
+                    Box::new(input.into_iter())
+                }
+                Source::Tar(archive) => match archive.entries() {
+                    Ok(entries) => Box::new(entries.filter_map(|entry| {
+                        let member = entry.and_then(|entry| {
+                            let name = entry.path()?.display().to_string();
+                            let jsonl =
+                                entry.header().entry_type().is_file() && name.ends_with(".jsonl");
+                            let input: Box<dyn BufRead + 's> = Box::new(BufReader::new(entry));
+                            Ok(jsonl.then_some((name, input)))
+                        });
+                        member.transpose()
+                    })),
+                    Err(e) => Box::new(std::iter::once(Err(e))),
+                },
+            }
+        })
+}
+
+/// Reads up to `len` bytes from the start of `input` and returns them along with a reader of the
+/// whole input
+fn sniff<'a, R>(mut input: R, len: usize) -> anyhow::Result<(Vec<u8>, impl BufRead + 'a)>
+where
+    R: BufRead + 'a,
+{
+    let mut header = vec![0; len];
+    let mut n = 0;
+    while n < len {
+        match input.read(&mut header[n..]) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e).context("failed to read input"),
+        }
+    }
+    header.truncate(n);
+    // Put the sniffed bytes back in front of the rest of the input
+    let input = io::Cursor::new(header.clone()).chain(input);
+    Ok((header, input))
+}
+
+/// Returns a reader decompressing `input` if it starts with the gzip magic bytes, or reading it as
+/// is otherwise
+fn decompress_gzip<'a, R>(input: R) -> anyhow::Result<Box<dyn BufRead + 'a>>
+where
+    R: BufRead + 'a,
+{
+    let (magic, input) = sniff(input, GZIP_MAGIC.len())?;
+    if magic == GZIP_MAGIC {
+        let decoder = MultiGzDecoder::new(Profiled::new(input, Stage::Read));
+        Ok(Box::new(BufReader::new(Profiled::new(
+            decoder,
+            Stage::Decompress,
+        ))))
+    } else {
+        Ok(Box::new(input))
     }
 }
 
@@ -95,91 +775,375 @@ fn open_input(p: &Path) -> anyhow::Result<BufReader<File>> {
     })?))
 }
 
-fn open_output(p: &Path) -> anyhow::Result<BufWriter<File>> {
-    Ok(BufWriter::new(File::create(p).with_context(|| {
-        format!("failed to open {}", p.display())
-    })?))
+fn open_output(p: &Path) -> anyhow::Result<BufWriter<Output>> {
+    #[cfg(unix)]
+    if let Some(socket) = p.to_str().and_then(|p| p.strip_prefix("unix:")) {
+        let socket = UnixStream::connect(socket)
+            .with_context(|| format!("failed to connect to {socket}"))?;
+        return Ok(BufWriter::new(Output::Socket(socket)));
+    }
+    Ok(BufWriter::new(Output::File(File::create(p).with_context(
+        || format!("failed to open {}", p.display()),
+    )?)))
 }
 
-fn process<I, O>(input: I, output: O) -> anyhow::Result<()>
+/// Calls `write` with the output at `p` and the path of the file it writes to
+///
+/// With `atomic`, that file is a temporary one, renamed to `p` once `write` succeeds and removed
+/// if it fails.
+fn write_output<T, F>(p: &Path, atomic: bool, write: F) -> anyhow::Result<T>
 where
-    I: BufRead,
-    O: Write,
+    F: FnOnce(BufWriter<Output>, &Path) -> anyhow::Result<T>,
 {
-    let mut output = csv::Writer::from_writer(output);
-    for (i, r) in records(input).enumerate() {
-        let r = r.with_context(|| format!("error on line {}", i + 1))?;
-        if r.avg_rate.is_some_and(|r| r <= 30.0) {
-            output.serialize(r).context("failed to write record")?;
+    if !atomic {
+        return write(open_output(p)?, p);
+    }
+    if p.to_str().is_some_and(|p| p.starts_with("unix:")) {
+        anyhow::bail!("--atomic-output requires an output file");
+    }
+    let (temp, file) = TempOutput::create(p)?;
+    // The writer is dropped, and its buffer flushed, by the time `write` returns
+    let value = write(BufWriter::new(Output::File(file)), &temp.path)?;
+    temp.persist()?;
+    Ok(value)
+}
+
+/// Temporary file written instead of an output file with `--atomic-output`, removed when dropped
+/// unless persisted
+struct TempOutput {
+    path: PathBuf,
+    target: PathBuf,
+    persisted: bool,
+}
+
+/// Number of names tried for a temporary output file before giving up
+const TEMP_OUTPUT_ATTEMPTS: u32 = 16;
+
+impl TempOutput {
+    /// Creates a new temporary file next to `target`, named after it with the process id and a
+    /// random suffix so that concurrent runs and existing files are left alone
+    fn create(target: &Path) -> anyhow::Result<(Self, File)> {
+        let mut attempts = 0;
+        loop {
+            let mut path = target.as_os_str().to_owned();
+            path.push(format!(
+                ".{}.{:08x}.tmp",
+                std::process::id(),
+                rand::random::<u32>()
+            ));
+            let path = PathBuf::from(path);
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    let temp = Self {
+                        path,
+                        target: target.to_owned(),
+                        persisted: false,
+                    };
+                    return Ok((temp, file));
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    attempts += 1;
+                    if attempts == TEMP_OUTPUT_ATTEMPTS {
+                        return Err(e)
+                            .with_context(|| format!("failed to open {}", path.display()));
+                    }
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("failed to open {}", path.display()));
+                }
+            }
         }
     }
-    output.flush()?;
-    Ok(())
+
+    /// Renames the temporary file over the output file
+    fn persist(mut self) -> anyhow::Result<()> {
+        File::open(&self.path)
+            .and_then(|file| file.sync_all())
+            .and_then(|()| fs::rename(&self.path, &self.target))
+            .with_context(|| format!("failed to write {}", self.target.display()))?;
+        self.persisted = true;
+        Ok(())
+    }
 }
 
-fn records<I>(input: I) -> impl Iterator<Item = anyhow::Result<Record>>
-where
-    I: BufRead,
-{
-    input.lines().map(|line| {
-        let line = line.context("failed to read line")?;
-        serde_json::from_str(&line).context("failed to parse record")
-    })
+impl Drop for TempOutput {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Destination of output named by a path
+enum Output {
+    File(File),
+    #[cfg(unix)]
+    Socket(UnixStream),
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::File(file) => file.write(buf),
+            #[cfg(unix)]
+            Output::Socket(socket) => socket.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::File(file) => file.flush(),
+            #[cfg(unix)]
+            Output::Socket(socket) => socket.flush(),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::Record;
-    use serde_json::json;
+    use crate::{
+        Cli, CountingReader, Tee, changes, config_args, decompress_gzip, jsonl_inputs, open_source,
+        part_path, write_output,
+    };
+    use clap::{CommandFactory, FromArgMatches};
+    use flate2::{Compression, write::GzEncoder};
+    use notify::{
+        Event, EventKind,
+        event::{AccessKind, ModifyKind},
+    };
+    use rust_etl_code_test::{ProcessOptions, process};
+    use std::{
+        fs,
+        io::{BufRead, Read, Write},
+        path::Path,
+    };
 
     #[test]
-    fn average_is_calculated() {
-        let input = json!({
-            "name": "alpha",
-            "billing_code": "1",
-            "negotiated_rates": [
-                {
-                    "negotiated_prices": [
-                        {
-                            "negotiated_rate": 10,
-                        },
-                    ],
-                },
-                {
-                    "negotiated_prices": [],
-                },
-                {
-                    "negotiated_prices": [
-                        {
-                            "negotiated_rate": 20,
-                        },
-                        {
-                            "negotiated_rate": 60,
-                        },
-                    ],
-                },
-            ],
-        });
+    fn every_option_has_an_env_var() {
+        for arg in Cli::command().get_arguments() {
+            let env = arg.get_env().and_then(|e| e.to_str());
+            let expected = format!("ETL_{}", arg.get_id().as_str().to_uppercase());
+            assert_eq!(env, Some(expected.as_str()), "{}", arg.get_id());
+        }
+    }
+
+    #[test]
+    fn config_sets_options_not_set_by_flags() {
+        let matches = Cli::command()
+            .try_get_matches_from(["etl", "--max-rate", "5"])
+            .unwrap();
+        let config = r#"{
+            "max_rate": 10,
+            "min_rate": 1.5,
+            "billing_code": ["a", "b"],
+            "include_null_rate": true,
+            "no_filter": false,
+            "format": "ndjson",
+            "shard": null
+        }"#;
+        let args = config_args(config, &matches).unwrap();
+        let args = ["etl", "--max-rate", "5"]
+            .into_iter()
+            .map(String::from)
+            .chain(args);
+        let options = Cli::from_arg_matches(&Cli::command().try_get_matches_from(args).unwrap())
+            .unwrap()
+            .options;
+        assert_eq!(options.max_rate, 5.0);
+        assert_eq!(options.min_rate, Some(1.5));
+        assert_eq!(options.billing_code, ["a", "b"]);
+        assert!(options.include_null_rate);
+        assert!(!options.no_filter);
+        assert_eq!(options.format, rust_etl_code_test::OutputFormat::Ndjson);
+        assert_eq!(options.shard, None);
+        let error = config_args(r#"{"max-rate": 10}"#, &matches).unwrap_err();
+        assert_eq!(error.to_string(), "unknown option `max-rate`");
+        let error = config_args(r#"{"max_rate": {"value": 10}}"#, &matches).unwrap_err();
+        assert!(error.to_string().starts_with("invalid value of `max_rate`"));
+        assert!(config_args("[]", &matches).is_err());
+    }
+
+    #[test]
+    fn counting_reader_counts_consumed_bytes() {
+        let mut input = CountingReader::new("first\nsecond\n".as_bytes());
+        input.read_line(&mut String::new()).unwrap();
+        assert_eq!(input.count, 6);
+    }
+
+    #[test]
+    fn part_number_is_inserted_before_extension() {
+        assert_eq!(
+            part_path(Path::new("out/rates.csv"), 1),
+            Path::new("out/rates.001.csv")
+        );
+        assert_eq!(part_path(Path::new("rates"), 12), Path::new("rates.012"));
+    }
+
+    #[test]
+    fn tee_writes_to_both_writers() {
+        let mut tee = Tee(Vec::new(), Vec::new());
+        write!(tee, "name,billing_code").unwrap();
+        assert_eq!(tee.0, b"name,billing_code");
+        assert_eq!(tee.1, b"name,billing_code");
+    }
+
+    #[test]
+    fn gzip_input_is_decompressed() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"{}\n").unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut input = String::new();
+        decompress_gzip(compressed.as_slice())
+            .unwrap()
+            .read_to_string(&mut input)
+            .unwrap();
+        assert_eq!(input, "{}\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn input_is_read_from_file_descriptor() {
+        use crate::{Source, default_source};
+        use clap::Parser;
+        use std::os::fd::IntoRawFd;
 
-        let expected = Record {
-            name: "alpha".into(),
-            billing_code: "1".into(),
-            avg_rate: Some(30.0),
+        let (reader, mut writer) = std::io::pipe().unwrap();
+        writer.write_all(b"{\"a\":1}\n").unwrap();
+        drop(writer);
+        let fd = reader.into_raw_fd().to_string();
+        let cli = Cli::try_parse_from(["etl", "--input-fd", &fd]).unwrap();
+        let Source::Jsonl(name, Some(mut input)) = default_source(&cli).unwrap() else {
+            panic!("input is not read as JSONL");
         };
+        let mut contents = String::new();
+        input.read_to_string(&mut contents).unwrap();
+        assert_eq!(name, format!("fd {fd}"));
+        assert_eq!(contents, "{\"a\":1}\n");
+        assert!(Cli::try_parse_from(["etl", "--input-fd", &fd, "--input", "a.jsonl"]).is_err());
+    }
 
-        let actual = serde_json::from_value::<Record>(input).unwrap();
-        assert_eq!(actual, expected);
+    #[test]
+    fn failed_atomic_output_leaves_target_untouched() {
+        let dir = std::env::temp_dir().join(format!("etl-atomic-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("output.csv");
+        fs::write(&target, "previous\n").unwrap();
+        let unrelated = dir.join("output.csv.tmp");
+        fs::write(&unrelated, "unrelated\n").unwrap();
+        let valid = r#"{"name":"a","billing_code":"1","negotiated_rates":[{"negotiated_prices":[{"negotiated_rate":10.0}]}]}"#;
+        let run = |input: String| {
+            write_output(&target, true, |writer, _| {
+                process(input.as_bytes(), writer, &ProcessOptions::default())
+            })
+        };
+        let failed = run(format!("{valid}\nnot json\n"));
+        let after_failure = fs::read_to_string(&target).unwrap();
+        let leftovers = fs::read_dir(&dir).unwrap().count();
+        let succeeded = run(format!("{valid}\n"));
+        let after_success = fs::read_to_string(&target).unwrap();
+        let unrelated = fs::read_to_string(&unrelated).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(failed.is_err());
+        assert_eq!(after_failure, "previous\n");
+        assert_eq!(leftovers, 2);
+        assert_eq!(unrelated, "unrelated\n");
+        succeeded.unwrap();
+        assert_eq!(after_success, "name,billing_code,avg_rate\na,1,10.0\n");
     }
 
+    #[cfg(unix)]
     #[test]
-    fn average_is_none_when_no_rates() {
-        let input = json!({
-            "name": "alpha",
-            "billing_code": "1",
-            "negotiated_rates": [],
+    fn output_is_streamed_to_unix_socket() {
+        use crate::open_output;
+        use std::{os::unix::net::UnixListener, path::PathBuf, thread};
+
+        let dir = std::env::temp_dir().join(format!("etl-socket-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("output.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let output = PathBuf::from(format!("unix:{}", socket.display()));
+        let reader = thread::spawn(move || {
+            let mut contents = String::new();
+            listener
+                .accept()
+                .unwrap()
+                .0
+                .read_to_string(&mut contents)
+                .unwrap();
+            let (peer, _) = listener.accept().unwrap();
+            drop(peer);
+            contents
         });
+        let mut writer = open_output(&output).unwrap();
+        writer.write_all(b"name,billing_code,avg_rate\n").unwrap();
+        drop(writer);
+        let mut writer = open_output(&output).unwrap();
+        let contents = reader.join().unwrap();
+        let written = (0..100).try_for_each(|_| writer.write_all(&[b'a'; 1 << 16]));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(contents, "name,billing_code,avg_rate\n");
+        assert!(written.is_err());
+    }
+
+    #[test]
+    fn jsonl_members_of_tar_archives_are_read() {
+        let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, contents) in [
+            ("a.jsonl", "{\"a\":1}\n"),
+            ("notes.txt", "not records"),
+            ("dir/b.jsonl", "{\"b\":2}\n"),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+        let compressed = archive.into_inner().unwrap().finish().unwrap();
+        let mut sources = [
+            open_source("archive.tar.gz".into(), compressed.as_slice()).unwrap(),
+            open_source("plain.jsonl".into(), "{}\n".as_bytes()).unwrap(),
+        ];
+        let inputs = jsonl_inputs(&mut sources)
+            .map(|input| {
+                let (name, mut input) = input.unwrap();
+                let mut contents = String::new();
+                input.read_to_string(&mut contents).unwrap();
+                (name, contents)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            inputs,
+            [
+                ("a.jsonl".to_owned(), "{\"a\":1}\n".to_owned()),
+                ("dir/b.jsonl".to_owned(), "{\"b\":2}\n".to_owned()),
+                ("plain.jsonl".to_owned(), "{}\n".to_owned()),
+            ]
+        );
+    }
 
-        let actual = serde_json::from_value::<Record>(input).unwrap();
-        assert_eq!(actual.avg_rate, None);
+    #[test]
+    fn plain_input_is_read_as_is() {
+        for plain in ["", "{", "{}\n"] {
+            let mut input = String::new();
+            decompress_gzip(plain.as_bytes())
+                .unwrap()
+                .read_to_string(&mut input)
+                .unwrap();
+            assert_eq!(input, plain);
+        }
+    }
+
+    #[test]
+    fn only_changes_to_the_input_are_watched() {
+        let input = Path::new("data/rates.jsonl");
+        let event = |kind, p: &str| Ok(Event::new(kind).add_path(p.into()));
+        let modify = EventKind::Modify(ModifyKind::Any);
+        assert!(changes(input, &event(modify, "data/rates.jsonl")));
+        assert!(!changes(input, &event(modify, "data/other.jsonl")));
+        let access = EventKind::Access(AccessKind::Any);
+        assert!(!changes(input, &event(access, "data/rates.jsonl")));
     }
 }